@@ -0,0 +1,242 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::Error;
+
+/// Channel of the image pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    Luma,
+    Index,
+    Coefficient,
+    Alpha,
+}
+
+/// Initials of the channels that can be selected, the single channel of grayscale and
+/// palette images and the DCT coefficients of JPEG images are not named
+const NAMES: [(char, Channel); 4] = [
+    ('r', Channel::Red),
+    ('g', Channel::Green),
+    ('b', Channel::Blue),
+    ('a', Channel::Alpha),
+];
+
+/// Channels of the image used to embed the payload, all the colour channels by default.
+/// Parsed from the channel initials, e.g. `b`, `gb` or `rgba`.
+/// Grayscale, palette and JPEG images use their single channel or all their DCT coefficients
+/// as long as any colour channel is selected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Channels {
+    selected: [bool; NAMES.len()],
+}
+
+impl Channels {
+    pub fn contains(&self, channel: Channel) -> bool {
+        match channel {
+            Channel::Luma | Channel::Index | Channel::Coefficient => {
+                self.selected[..3].iter().any(|&s| s)
+            }
+            _ => NAMES
+                .iter()
+                .zip(&self.selected)
+                .any(|((_, c), &selected)| *c == channel && selected),
+        }
+    }
+
+    /// Iterator over the indices of the image bytes belonging to the selected channels, in image order.
+    /// `layout` lists the channels of a pixel in the order they are interleaved in the image bytes
+    pub fn indices<'a>(
+        &self,
+        layout: &'a [Channel],
+        len: usize,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let selected = *self;
+        (0..len).filter(move |i| selected.contains(layout[i % layout.len()]))
+    }
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Channels {
+            selected: [true, true, true, false],
+        }
+    }
+}
+
+impl FromStr for Channels {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut selected = [false; NAMES.len()];
+        for c in s.to_lowercase().chars() {
+            let channel = NAMES
+                .iter()
+                .position(|&(name, _)| name == c)
+                .ok_or(Error::InvalidChannels)?;
+            selected[channel] = true;
+        }
+
+        if selected.iter().any(|&s| s) {
+            Ok(Channels { selected })
+        } else {
+            Err(Error::InvalidChannels)
+        }
+    }
+}
+
+impl fmt::Display for Channels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        NAMES
+            .iter()
+            .zip(&self.selected)
+            .filter(|(_, &selected)| selected)
+            .try_for_each(|((name, _), _)| write!(f, "{}", name))
+    }
+}
+
+/// Number of bits per sample of each channel, parsed from channel initials followed by their bits,
+/// e.g. `r2,g1,b3`. Channels not listed hold no bits, the single channel of grayscale, palette
+/// and JPEG images uses the most bits of the colour channels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelBits {
+    bits: [u8; NAMES.len()],
+}
+
+impl ChannelBits {
+    pub fn get(&self, channel: Channel) -> u8 {
+        match channel {
+            Channel::Luma | Channel::Index | Channel::Coefficient => {
+                self.bits[..3].iter().copied().max().unwrap_or(0)
+            }
+            _ => NAMES
+                .iter()
+                .zip(&self.bits)
+                .find(|((_, c), _)| *c == channel)
+                .map_or(0, |(_, &bits)| bits),
+        }
+    }
+
+    /// Most bits per sample of any channel
+    pub fn max(&self) -> u8 {
+        self.bits.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        self.bits.to_vec()
+    }
+
+    /// Parses the bits of every channel from the given bytes, only the bytes belonging to them are consumed
+    pub fn read<I: Iterator<Item = u8>>(bytes: &mut I) -> Result<Self, Error> {
+        let mut channel_bits = ChannelBits {
+            bits: [0; NAMES.len()],
+        };
+        for bits in channel_bits.bits.iter_mut() {
+            *bits = bytes.next().ok_or(Error::InvalidHeader)?;
+        }
+
+        if (1..=16).contains(&channel_bits.max()) {
+            Ok(channel_bits)
+        } else {
+            Err(Error::InvalidHeader)
+        }
+    }
+}
+
+impl FromStr for ChannelBits {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut bits = [0; NAMES.len()];
+        for spec in s.to_lowercase().split(',') {
+            let mut chars = spec.trim().chars();
+            let channel = chars
+                .next()
+                .and_then(|c| NAMES.iter().position(|&(name, _)| name == c))
+                .ok_or(Error::InvalidChannels)?;
+            if bits[channel] != 0 {
+                return Err(Error::InvalidChannels);
+            }
+            bits[channel] = match chars.as_str().parse() {
+                Ok(b @ 1..=16) => b,
+                _ => return Err(Error::InvalidNumberOfBits),
+            };
+        }
+        Ok(ChannelBits { bits })
+    }
+}
+
+impl fmt::Display for ChannelBits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let specs: Vec<String> = NAMES
+            .iter()
+            .zip(&self.bits)
+            .filter(|(_, &bits)| bits > 0)
+            .map(|((name, _), bits)| format!("{}{}", name, bits))
+            .collect();
+        write!(f, "{}", specs.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, ChannelBits, Channels};
+
+    const RGB: [Channel; 3] = [Channel::Red, Channel::Green, Channel::Blue];
+
+    #[test]
+    fn test_parse() {
+        let channels: Channels = "GB".parse().unwrap();
+        assert_eq!(channels.to_string(), "gb");
+        assert_eq!(Channels::default().to_string(), "rgb");
+        assert_eq!("bb".parse::<Channels>().unwrap().to_string(), "b");
+        assert_eq!("argb".parse::<Channels>().unwrap().to_string(), "rgba");
+        assert!("".parse::<Channels>().is_err());
+        assert!("rgbl".parse::<Channels>().is_err());
+    }
+
+    #[test]
+    fn test_indices() {
+        let channels: Channels = "rb".parse().unwrap();
+        assert!(channels.indices(&RGB, 7).eq([0, 2, 3, 5, 6]));
+        assert!(Channels::default().indices(&RGB, 4).eq([0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn test_alpha() {
+        let rgba = [Channel::Red, Channel::Green, Channel::Blue, Channel::Alpha];
+        let luma_alpha = [Channel::Luma, Channel::Alpha];
+
+        assert!(Channels::default().indices(&rgba, 8).eq([0, 1, 2, 4, 5, 6]));
+        let alpha: Channels = "a".parse().unwrap();
+        assert!(alpha.indices(&rgba, 8).eq([3, 7]));
+        assert!(alpha.indices(&luma_alpha, 4).eq([1, 3]));
+        assert!(Channels::default().indices(&luma_alpha, 4).eq([0, 2]));
+        assert_eq!(alpha.indices(&RGB, 6).count(), 0);
+    }
+
+    #[test]
+    fn test_channel_bits() {
+        let bits: ChannelBits = "B3, r2,g1".parse().unwrap();
+        assert_eq!(bits.to_string(), "r2,g1,b3");
+        assert_eq!(bits.get(Channel::Blue), 3);
+        assert_eq!(bits.get(Channel::Alpha), 0);
+        assert_eq!(bits.get(Channel::Luma), 3);
+        assert_eq!(bits.max(), 3);
+
+        assert!("".parse::<ChannelBits>().is_err());
+        assert!("r2,r3".parse::<ChannelBits>().is_err());
+        assert!("l2".parse::<ChannelBits>().is_err());
+        assert!("r0".parse::<ChannelBits>().is_err());
+        assert!("r17".parse::<ChannelBits>().is_err());
+        assert!("rgb".parse::<ChannelBits>().is_err());
+
+        let bytes = bits.to_bytes();
+        assert_eq!(bytes, vec![2, 1, 3, 0]);
+        assert_eq!(ChannelBits::read(&mut bytes.into_iter()).unwrap(), bits);
+        assert!(ChannelBits::read(&mut vec![0, 0, 0, 0].into_iter()).is_err());
+        assert!(ChannelBits::read(&mut vec![2, 1].into_iter()).is_err());
+    }
+}
@@ -0,0 +1,448 @@
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+use image::{DynamicImage, GenericImageView, ImageBuffer, ImageFormat};
+
+use crate::channels::Channel;
+use crate::errors::Error;
+use crate::jpeg::Jpeg;
+use crate::palette::Palette;
+
+/// Pixel layouts kept as they are in the source image
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Layout {
+    Rgb,
+    Rgba,
+    Luma,
+    LumaAlpha,
+}
+
+impl Layout {
+    /// Channels of a pixel, in the order they are interleaved in the image samples
+    fn channels(self) -> &'static [Channel] {
+        match self {
+            Layout::Rgb => &[Channel::Red, Channel::Green, Channel::Blue],
+            Layout::Rgba => &[Channel::Red, Channel::Green, Channel::Blue, Channel::Alpha],
+            Layout::Luma => &[Channel::Luma],
+            Layout::LumaAlpha => &[Channel::Luma, Channel::Alpha],
+        }
+    }
+}
+
+/// Representation of the image the samples are taken from and written back to
+enum Carrier {
+    /// Pixel samples, 8 or 16 bits deep
+    Pixels {
+        width: u32,
+        height: u32,
+        layout: Layout,
+        depth: u8,
+    },
+    /// Indices into the sorted palette
+    Palette(Palette),
+    /// Magnitudes of the quantised DCT coefficients of a JPEG image
+    Jpeg(Jpeg),
+}
+
+/// Image carrying the payload, keeping the depth of the source image, 8 or 16 bits per sample.
+/// Grayscale images and images with an alpha channel keep their layout, any other image is converted to RGB.
+/// Indexed PNGs and GIFs stay indexed, their samples are the indices into the sorted palette.
+/// JPEG images can be opened as their DCT coefficients, which are saved back without recompression.
+/// Dereferences to the image samples, interleaved pixel by pixel
+pub struct Cover {
+    carrier: Carrier,
+    samples: Vec<u16>,
+}
+
+impl Cover {
+    /// Opens the image, JPEG images are opened as DCT coefficients if `dct` is set
+    pub fn open(path: &Path, dct: bool) -> Result<Self, Error> {
+        if dct {
+            if let Some(jpeg) = Jpeg::open(path)? {
+                return Ok(Cover {
+                    samples: jpeg.samples(),
+                    carrier: Carrier::Jpeg(jpeg),
+                });
+            }
+        }
+
+        if let Some((palette, indices)) = Palette::open(path)? {
+            return Ok(Cover {
+                carrier: Carrier::Palette(palette),
+                samples: widen(indices),
+            });
+        }
+
+        // The format is guessed from the contents, the extension may not match when saved with another format
+        let image = image::io::Reader::open(path)?
+            .with_guessed_format()?
+            .decode()?;
+        let (width, height) = (image.width(), image.height());
+
+        let (layout, depth, samples) = match image {
+            DynamicImage::ImageLuma8(image) => (Layout::Luma, 8, widen(image.into_raw())),
+            DynamicImage::ImageLuma16(image) => (Layout::Luma, 16, image.into_raw()),
+            DynamicImage::ImageLumaA8(image) => (Layout::LumaAlpha, 8, widen(image.into_raw())),
+            DynamicImage::ImageLumaA16(image) => (Layout::LumaAlpha, 16, image.into_raw()),
+            DynamicImage::ImageRgba8(image) => (Layout::Rgba, 8, widen(image.into_raw())),
+            DynamicImage::ImageBgra8(_) => (Layout::Rgba, 8, widen(image.to_rgba8().into_raw())),
+            DynamicImage::ImageRgba16(image) => (Layout::Rgba, 16, image.into_raw()),
+            DynamicImage::ImageRgb16(image) => (Layout::Rgb, 16, image.into_raw()),
+            _ => (Layout::Rgb, 8, widen(image.to_rgb8().into_raw())),
+        };
+
+        Ok(Cover {
+            carrier: Carrier::Pixels {
+                width,
+                height,
+                layout,
+                depth,
+            },
+            samples,
+        })
+    }
+
+    /// Channels of a pixel, in the order they are interleaved in the image samples
+    pub fn channels(&self) -> &'static [Channel] {
+        match &self.carrier {
+            Carrier::Pixels { layout, .. } => layout.channels(),
+            Carrier::Palette(_) => &[Channel::Index],
+            Carrier::Jpeg(_) => &[Channel::Coefficient],
+        }
+    }
+
+    /// Largest value a sample can take, the last index for palette images
+    pub fn max(&self) -> u16 {
+        match &self.carrier {
+            Carrier::Pixels { depth, .. } => (u32::pow(2, *depth as u32) - 1) as u16,
+            Carrier::Palette(palette) => palette.len().saturating_sub(1) as u16,
+            Carrier::Jpeg(_) => u16::MAX,
+        }
+    }
+
+    /// Largest number of LSBs of a sample that can hold the payload
+    pub fn max_bits(&self) -> u8 {
+        match &self.carrier {
+            Carrier::Pixels { depth, .. } => *depth,
+            Carrier::Palette(palette) => palette.len().checked_ilog2().unwrap_or(0) as u8,
+            // Only the LSB keeps the coefficients in their Huffman size category
+            Carrier::Jpeg(_) => 1,
+        }
+    }
+
+    /// Whether the samples are indices into a palette
+    pub fn indexed(&self) -> bool {
+        matches!(self.carrier, Carrier::Palette(_))
+    }
+
+    /// Leaves out the samples whose given number of LSBs can not change without changing their transparency,
+    /// only palette indices next to entries of another transparency level. Those LSBs do not affect the selection
+    pub fn retain_alpha(&self, samples: &mut Vec<usize>, bits: u8) {
+        if let Carrier::Palette(palette) = &self.carrier {
+            samples.retain(|&i| palette.same_alpha(self.samples[i], bits));
+        }
+    }
+
+    /// Whether the samples are DCT coefficients of a JPEG image
+    pub fn dct(&self) -> bool {
+        matches!(self.carrier, Carrier::Jpeg(_))
+    }
+
+    /// Cost of changing each sample, low in textured areas where changes are harder to detect.
+    /// The texture is measured by the differences to the neighbouring samples of the same channel
+    pub fn costs(&self) -> Vec<f32> {
+        let (width, stride) = match &self.carrier {
+            Carrier::Pixels { width, layout, .. } => (*width as usize, layout.channels().len()),
+            Carrier::Palette(palette) => (palette.width() as usize, 1),
+            // Larger coefficients belong to busier blocks
+            Carrier::Jpeg(_) => return self.samples.iter().map(|&s| 1.0 / s as f32).collect(),
+        };
+        let row = width * stride;
+
+        (0..self.samples.len())
+            .map(|i| {
+                let x = i / stride % width;
+                let neighbours = [
+                    i.checked_sub(stride).filter(|_| x > 0),
+                    Some(i + stride).filter(|_| x + 1 < width),
+                    i.checked_sub(row),
+                    Some(i + row).filter(|&n| n < self.samples.len()),
+                ];
+                let texture: f32 = neighbours
+                    .iter()
+                    .flatten()
+                    .map(|&n| (self.samples[i] as f32 - self.samples[n] as f32).abs())
+                    .sum();
+                1.0 / (1.0 + texture)
+            })
+            .collect()
+    }
+
+    /// Variance of the 3x3 neighbourhood of each sample in its channel, on the scale of 8-bit samples.
+    /// Only the bits outside the mask are taken into account, so changing the masked LSBs leaves it as it is
+    pub fn variances(&self, mask: u16) -> Result<Vec<f32>, Error> {
+        let (width, stride, depth) = match &self.carrier {
+            Carrier::Pixels {
+                width,
+                layout,
+                depth,
+                ..
+            } => (*width as usize, layout.channels().len(), *depth),
+            // Palette indices and DCT coefficients are not laid out as a grid of pixels
+            _ => return Err(Error::UnsupportedMode),
+        };
+        let row = width * stride;
+        let height = self.samples.len() / row;
+        let scale = f32::from(1u16 << (depth - 8));
+
+        let variances = (0..self.samples.len())
+            .map(|i| {
+                let (x, y, channel) = (i / stride % width, i / row, i % stride);
+                let values: Vec<f32> = (y.saturating_sub(1)..=(y + 1).min(height - 1))
+                    .flat_map(|y| {
+                        (x.saturating_sub(1)..=(x + 1).min(width - 1))
+                            .map(move |x| y * row + x * stride + channel)
+                    })
+                    .map(|n| (self.samples[n] & !mask) as f32 / scale)
+                    .collect();
+
+                let mean = values.iter().sum::<f32>() / values.len() as f32;
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32
+            })
+            .collect();
+        Ok(variances)
+    }
+
+    /// Whether saving in the given format would alter the samples, losing the payload
+    pub fn lossy(&self, format: ImageFormat) -> bool {
+        match format {
+            ImageFormat::Jpeg => !self.dct(),
+            // Truecolour images are quantised to a palette of 256 colours
+            ImageFormat::Gif => !self.indexed(),
+            ImageFormat::WebP | ImageFormat::Avif => true,
+            _ => false,
+        }
+    }
+
+    /// Saves the image in the given format, with the same pixel layout and depth it was opened with
+    pub fn save(&mut self, path: &Path, format: ImageFormat) -> Result<(), Error> {
+        let (width, height, layout, depth) = match &mut self.carrier {
+            Carrier::Pixels {
+                width,
+                height,
+                layout,
+                depth,
+            } => (*width, *height, *layout, *depth),
+            Carrier::Palette(palette) => {
+                let indices: Vec<u8> = self.samples.iter().map(|&s| s as u8).collect();
+                return palette.save(path, format, &indices);
+            }
+            // The coefficients can only be written back as JPEG
+            Carrier::Jpeg(_) if format != ImageFormat::Jpeg => return Err(Error::UnsupportedImage),
+            Carrier::Jpeg(jpeg) => {
+                jpeg.set_samples(&self.samples);
+                return jpeg.save(path);
+            }
+        };
+
+        let image = if depth == 8 {
+            let samples: Vec<u8> = self.samples.iter().map(|&s| s as u8).collect();
+            match layout {
+                Layout::Rgb => {
+                    ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb8)
+                }
+                Layout::Rgba => {
+                    ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba8)
+                }
+                Layout::Luma => {
+                    ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLuma8)
+                }
+                Layout::LumaAlpha => {
+                    ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA8)
+                }
+            }
+        } else {
+            let samples = self.samples.clone();
+            match layout {
+                Layout::Rgb => {
+                    ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb16)
+                }
+                Layout::Rgba => {
+                    ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16)
+                }
+                Layout::Luma => {
+                    ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLuma16)
+                }
+                Layout::LumaAlpha => {
+                    ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA16)
+                }
+            }
+        };
+
+        image
+            .ok_or(Error::ImageReadWrite)?
+            .save_with_format(path, format)?;
+        Ok(())
+    }
+}
+
+fn widen(bytes: Vec<u8>) -> Vec<u16> {
+    bytes.into_iter().map(u16::from).collect()
+}
+
+impl Deref for Cover {
+    type Target = [u16];
+
+    fn deref(&self) -> &[u16] {
+        &self.samples
+    }
+}
+
+impl DerefMut for Cover {
+    fn deref_mut(&mut self) -> &mut [u16] {
+        &mut self.samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, Cover};
+    use crate::utils::Tmp;
+    use image::{
+        DynamicImage, GrayAlphaImage, GrayImage, ImageBuffer, ImageFormat, Luma, LumaA, Rgb, Rgba,
+        RgbaImage,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn test_rgb() {
+        let cover = Cover::open(Path::new("./samples/the-matrix.jpg"), false).unwrap();
+        assert_eq!(cover.channels().len(), 3);
+        assert_eq!(cover.len(), 417600);
+        assert_eq!(cover.max(), 255);
+        assert_eq!(cover.max_bits(), 8);
+    }
+
+    #[test]
+    fn test_dct() {
+        let cover = Cover::open(Path::new("./samples/the-matrix.jpg"), true).unwrap();
+        assert!(cover.dct());
+        assert_eq!(cover.channels(), [Channel::Coefficient]);
+        assert_eq!(cover.max_bits(), 1);
+        assert!(cover.iter().all(|&sample| sample >= 2));
+
+        // Images other than JPEG are opened as usual
+        let cover = Cover::open(Path::new("./samples/the-matrix-reloaded.png"), true).unwrap();
+        assert!(!cover.dct());
+    }
+
+    #[test]
+    fn test_costs() {
+        let image = ImageBuffer::from_fn(8, 8, |x, _| {
+            Luma([if x < 4 { 100u8 } else { x as u8 * 30 }])
+        });
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("png");
+        DynamicImage::ImageLuma8(image).save(path).unwrap();
+
+        // Flat areas cost the most, textured areas the least
+        let costs = Cover::open(path, false).unwrap().costs();
+        assert_eq!(costs.len(), 64);
+        assert_eq!(costs[1], 1.0);
+        assert!(costs[6] < costs[1]);
+        assert!(costs[6] < costs[3]);
+    }
+
+    #[test]
+    fn test_variances() {
+        let image = ImageBuffer::from_fn(8, 4, |x, y| {
+            Rgb([x as u8 * 30 + y as u8, 48 + (x + y) as u8 % 2 * 3, 200])
+        });
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("png");
+        DynamicImage::ImageRgb8(image).save(path).unwrap();
+
+        let cover = Cover::open(path, false).unwrap();
+        let variances = cover.variances(0b11).unwrap();
+        assert_eq!(variances.len(), 8 * 4 * 3);
+
+        // Textured red, flat green once its LSBs are ignored, and constant blue
+        assert!(variances[3 * 3] > 100.0);
+        assert_eq!(variances[3 * 3 + 1], 0.0);
+        assert_eq!(variances[3 * 3 + 2], 0.0);
+
+        // Changes in the masked LSBs do not move the variances
+        let mut changed = cover;
+        changed.iter_mut().for_each(|sample| *sample ^= 0b10);
+        assert_eq!(changed.variances(0b11).unwrap(), variances);
+    }
+
+    #[test]
+    fn test_lossy() {
+        let pixels = Cover::open(Path::new("./samples/the-matrix.jpg"), false).unwrap();
+        let dct = Cover::open(Path::new("./samples/the-matrix.jpg"), true).unwrap();
+
+        assert!(pixels.lossy(ImageFormat::Jpeg));
+        assert!(!dct.lossy(ImageFormat::Jpeg));
+        assert!(pixels.lossy(ImageFormat::Gif));
+        assert!(pixels.lossy(ImageFormat::WebP));
+        assert!(!pixels.lossy(ImageFormat::Png));
+        assert!(!pixels.lossy(ImageFormat::Tiff));
+    }
+
+    #[test]
+    fn test_keeps_depth() {
+        let image = ImageBuffer::from_fn(16, 8, |x, y| Rgb([x as u16 * 4000, y as u16, 65535]));
+        let image = DynamicImage::ImageRgb16(image);
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("png");
+        image.save(path).unwrap();
+
+        let mut cover = Cover::open(path, false).unwrap();
+        assert_eq!(cover.max(), u16::MAX);
+        assert_eq!(cover[0..3], [0, 0, 65535]);
+        cover.save(path, ImageFormat::Png).unwrap();
+        assert_eq!(image::open(path).unwrap(), image);
+    }
+
+    #[test]
+    fn test_keeps_grayscale() {
+        let luma = GrayImage::from_fn(16, 8, |x, y| Luma([(x * y) as u8]));
+        let luma = DynamicImage::ImageLuma8(luma);
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("png");
+        luma.save(path).unwrap();
+
+        let mut cover = Cover::open(path, false).unwrap();
+        assert_eq!(cover.channels(), [Channel::Luma]);
+        assert_eq!(cover.len(), 16 * 8);
+        cover.save(path, ImageFormat::Png).unwrap();
+        assert_eq!(image::open(path).unwrap(), luma);
+    }
+
+    #[test]
+    fn test_keeps_alpha() {
+        let rgba = RgbaImage::from_fn(16, 8, |x, y| Rgba([x as u8, y as u8, 7, (x * y) as u8]));
+        let luma_alpha = GrayAlphaImage::from_fn(16, 8, |x, y| LumaA([x as u8, (x + y) as u8]));
+
+        let mut tmp = Tmp::default();
+        for (image, channels) in &[
+            (
+                DynamicImage::ImageRgba8(rgba),
+                &[Channel::Red, Channel::Green, Channel::Blue, Channel::Alpha][..],
+            ),
+            (
+                DynamicImage::ImageLumaA8(luma_alpha),
+                &[Channel::Luma, Channel::Alpha][..],
+            ),
+        ] {
+            let path = &tmp.path("png");
+            image.save(path).unwrap();
+
+            let mut cover = Cover::open(path, false).unwrap();
+            assert_eq!(cover.channels(), *channels);
+            cover.save(path, ImageFormat::Png).unwrap();
+            assert_eq!(image::open(path).unwrap(), *image);
+        }
+    }
+}
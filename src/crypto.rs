@@ -0,0 +1,313 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::errors::Error;
+
+pub const KEY_SIZE: usize = 32;
+pub const SALT_SIZE: usize = 16;
+pub const NONCE_SIZE: usize = 12;
+
+/// Size of the authentication tag appended to every ciphertext
+pub const TAG_SIZE: usize = 16;
+
+/// Domain separation for the X25519 wrapping key derivation
+const WRAP_INFO: &[u8] = b"piss/x25519";
+
+/// File key wrapped for a single recipient: the ephemeral public key followed by the
+/// file key encrypted under the key shared between the ephemeral secret and the recipient
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stanza {
+    pub ephemeral: [u8; KEY_SIZE],
+    pub wrapped: [u8; KEY_SIZE + TAG_SIZE],
+}
+
+impl Stanza {
+    /// Number of bytes a stanza takes once serialized
+    pub const SIZE: usize = KEY_SIZE * 2 + TAG_SIZE;
+
+    fn wrap(recipient: &PublicKey, file_key: &[u8; KEY_SIZE]) -> Result<Self, Error> {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(recipient);
+
+        let cipher = wrapping_cipher(shared.as_bytes(), &ephemeral, recipient);
+        let wrapped = cipher
+            .encrypt(Nonce::from_slice(&[0; NONCE_SIZE]), &file_key[..])
+            .map_err(|_| Error::InvalidKey)?;
+
+        let mut stanza = Stanza {
+            ephemeral: ephemeral.to_bytes(),
+            wrapped: [0; KEY_SIZE + TAG_SIZE],
+        };
+        stanza.wrapped.copy_from_slice(&wrapped);
+        Ok(stanza)
+    }
+
+    /// Recovers the file key, returns `None` if the stanza was not wrapped for this identity
+    fn unwrap(&self, identity: &StaticSecret) -> Option<[u8; KEY_SIZE]> {
+        let ephemeral = PublicKey::from(self.ephemeral);
+        let shared = identity.diffie_hellman(&ephemeral);
+
+        let cipher = wrapping_cipher(shared.as_bytes(), &ephemeral, &PublicKey::from(identity));
+        let file_key = cipher
+            .decrypt(Nonce::from_slice(&[0; NONCE_SIZE]), &self.wrapped[..])
+            .ok()?;
+
+        let mut key = [0; KEY_SIZE];
+        key.copy_from_slice(&file_key);
+        Some(key)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        [&self.ephemeral[..], &self.wrapped[..]].concat()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut stanza = Stanza {
+            ephemeral: [0; KEY_SIZE],
+            wrapped: [0; KEY_SIZE + TAG_SIZE],
+        };
+        stanza.ephemeral.copy_from_slice(&bytes[..KEY_SIZE]);
+        stanza.wrapped.copy_from_slice(&bytes[KEY_SIZE..Self::SIZE]);
+        stanza
+    }
+}
+
+/// Derives the cipher wrapping the file key from the X25519 shared secret, binding both public keys
+fn wrapping_cipher(
+    shared: &[u8],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> ChaCha20Poly1305 {
+    let salt = [&ephemeral.as_bytes()[..], &recipient.as_bytes()[..]].concat();
+    let mut key = [0; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// Parameters of the secret encryption, stored in the payload header.
+/// The secret is always encrypted with ChaCha20-Poly1305, they describe how to obtain its key
+#[derive(Clone, Debug, PartialEq)]
+pub enum Encryption {
+    /// Key derived from a password with Argon2id
+    Password {
+        salt: [u8; SALT_SIZE],
+        nonce: [u8; NONCE_SIZE],
+    },
+    /// Random key wrapped for each of the recipients with X25519
+    Recipients {
+        nonce: [u8; NONCE_SIZE],
+        stanzas: Vec<Stanza>,
+    },
+}
+
+impl Encryption {
+    /// Generates random parameters for the password, returns them together with the derived key.
+    /// They must never be reused for a different secret
+    pub fn password(password: &str) -> Result<(Self, [u8; KEY_SIZE]), Error> {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let encryption = Encryption::Password {
+            salt,
+            nonce: random_nonce(),
+        };
+        let key = encryption.password_key(password)?;
+        Ok((encryption, key))
+    }
+
+    /// Generates a random key and wraps it for every recipient, returns the parameters together with the key
+    pub fn recipients(recipients: &[PublicKey]) -> Result<(Self, [u8; KEY_SIZE]), Error> {
+        // The number of stanzas is stored in a single byte
+        if recipients.is_empty() || recipients.len() > u8::MAX as usize {
+            return Err(Error::InvalidKey);
+        }
+
+        let mut file_key = [0; KEY_SIZE];
+        OsRng.fill_bytes(&mut file_key);
+
+        let stanzas = recipients
+            .iter()
+            .map(|recipient| Stanza::wrap(recipient, &file_key))
+            .collect::<Result<_, _>>()?;
+
+        let encryption = Encryption::Recipients {
+            nonce: random_nonce(),
+            stanzas,
+        };
+        Ok((encryption, file_key))
+    }
+
+    /// Derives the key from the password, fails if the secret was not encrypted with a password
+    pub fn password_key(&self, password: &str) -> Result<[u8; KEY_SIZE], Error> {
+        match self {
+            Encryption::Password { salt, .. } => {
+                let mut key = [0; KEY_SIZE];
+                Argon2::default()
+                    .hash_password_into(password.as_bytes(), salt, &mut key)
+                    .map_err(|_| Error::WrongPassword)?;
+                Ok(key)
+            }
+            Encryption::Recipients { .. } => Err(Error::WrongIdentity),
+        }
+    }
+
+    /// Unwraps the key with the identity, fails if the secret was not encrypted to it
+    pub fn identity_key(&self, identity: &StaticSecret) -> Result<[u8; KEY_SIZE], Error> {
+        match self {
+            Encryption::Recipients { stanzas, .. } => stanzas
+                .iter()
+                .find_map(|stanza| stanza.unwrap(identity))
+                .ok_or(Error::WrongIdentity),
+            Encryption::Password { .. } => Err(Error::WrongPassword),
+        }
+    }
+
+    fn nonce(&self) -> &[u8; NONCE_SIZE] {
+        match self {
+            Encryption::Password { nonce, .. } | Encryption::Recipients { nonce, .. } => nonce,
+        }
+    }
+
+    /// Encrypts the plaintext, authenticating the associated data along with it
+    pub fn encrypt(
+        &self,
+        key: &[u8; KEY_SIZE],
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        ChaCha20Poly1305::new(Key::from_slice(key))
+            .encrypt(Nonce::from_slice(self.nonce()), payload)
+            .map_err(|_| Error::SecretTooLarge)
+    }
+
+    /// Fails if the ciphertext and associated data do not authenticate with the key
+    pub fn decrypt(
+        &self,
+        key: &[u8; KEY_SIZE],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let error = match self {
+            Encryption::Password { .. } => Error::WrongPassword,
+            Encryption::Recipients { .. } => Error::WrongIdentity,
+        };
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        ChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(Nonce::from_slice(self.nonce()), payload)
+            .map_err(|_| error)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Encryption::Password { salt, nonce } => [&salt[..], &nonce[..]].concat(),
+            Encryption::Recipients { nonce, stanzas } => {
+                let mut bytes = vec![stanzas.len() as u8];
+                bytes.extend_from_slice(nonce);
+                stanzas
+                    .iter()
+                    .for_each(|stanza| bytes.append(&mut stanza.to_bytes()));
+                bytes
+            }
+        }
+    }
+
+    /// Parses password parameters, `bytes` must hold the salt followed by the nonce
+    pub fn password_from_bytes(bytes: &[u8]) -> Self {
+        let mut salt = [0; SALT_SIZE];
+        let mut nonce = [0; NONCE_SIZE];
+        salt.copy_from_slice(&bytes[..SALT_SIZE]);
+        nonce.copy_from_slice(&bytes[SALT_SIZE..SALT_SIZE + NONCE_SIZE]);
+        Encryption::Password { salt, nonce }
+    }
+
+    /// Parses recipients parameters, `bytes` must hold the number of stanzas, the nonce and the stanzas
+    pub fn recipients_from_bytes(bytes: &[u8]) -> Self {
+        let mut nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&bytes[1..=NONCE_SIZE]);
+        let stanzas = bytes[NONCE_SIZE + 1..]
+            .chunks_exact(Stanza::SIZE)
+            .map(Stanza::from_bytes)
+            .collect();
+        Encryption::Recipients { nonce, stanzas }
+    }
+}
+
+fn random_nonce() -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encryption;
+    use rand::rngs::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    #[test]
+    fn test_password() {
+        let (encryption, key) = Encryption::password("red pill").unwrap();
+        let ciphertext = encryption
+            .encrypt(&key, b"The Matrix has you.", b"header")
+            .unwrap();
+        assert_ne!(&ciphertext[..19], b"The Matrix has you.");
+
+        let encryption = Encryption::password_from_bytes(&encryption.to_bytes());
+        let key = encryption.password_key("red pill").unwrap();
+        let plaintext = encryption.decrypt(&key, &ciphertext, b"header").unwrap();
+        assert_eq!(plaintext, b"The Matrix has you.");
+
+        // The associated data is authenticated along with the ciphertext
+        assert!(encryption.decrypt(&key, &ciphertext, b"Header").is_err());
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let (encryption, key) = Encryption::password("red pill").unwrap();
+        let ciphertext = encryption
+            .encrypt(&key, b"The Matrix has you.", b"")
+            .unwrap();
+        let key = encryption.password_key("blue pill").unwrap();
+        assert!(encryption.decrypt(&key, &ciphertext, b"").is_err());
+    }
+
+    #[test]
+    fn test_recipients() {
+        let neo = StaticSecret::random_from_rng(OsRng);
+        let trinity = StaticSecret::random_from_rng(OsRng);
+        let smith = StaticSecret::random_from_rng(OsRng);
+
+        let recipients = [PublicKey::from(&neo), PublicKey::from(&trinity)];
+        let (encryption, key) = Encryption::recipients(&recipients).unwrap();
+        let ciphertext = encryption
+            .encrypt(&key, b"The Matrix has you.", b"")
+            .unwrap();
+
+        let encryption = Encryption::recipients_from_bytes(&encryption.to_bytes());
+        for identity in &[neo, trinity] {
+            let key = encryption.identity_key(identity).unwrap();
+            let plaintext = encryption.decrypt(&key, &ciphertext, b"").unwrap();
+            assert_eq!(plaintext, b"The Matrix has you.");
+        }
+
+        assert!(encryption.identity_key(&smith).is_err());
+        assert!(encryption.password_key("red pill").is_err());
+    }
+}
@@ -1,80 +1,299 @@
-use std::fs::File;
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 
-use image::{ImageBuffer, Rgb};
+use ed25519_dalek::VerifyingKey;
 
+use crate::cover::Cover;
+use crate::ecc;
 use crate::errors::Error;
-use crate::utils::ByteMask;
+use crate::matrix;
+use crate::metadata::Metadata;
+use crate::options::Options;
+use crate::payload;
+use crate::preamble::{Mode, Preamble};
+use crate::scatter;
+use crate::stc;
+use crate::utils::{self, ByteMask};
 
 pub struct Decoder {
-    image: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    image: Cover,
     mask: ByteMask,
+    mode: Mode,
+    /// Bits per sample of each channel of the image layout, a single one unless given per channel
+    lanes: Vec<u8>,
+    order: Vec<usize>,
+    /// Reed-Solomon parity bytes per codeword of the payload
+    ecc: Option<u8>,
+    /// Number of corrupted payload bytes fixed by the last read
+    corrected: Cell<usize>,
+    options: Options,
 }
 
 impl Decoder {
-    pub fn new(image_path: PathBuf, mask: ByteMask) -> Result<Self, Error> {
-        let image = image::open(image_path)?.to_rgb();
-        Ok(Decoder { image, mask })
-    }
+    /// Opens the image and reads its preamble, failing if a mask is given and differs from the stored one
+    pub fn new(
+        image_path: PathBuf,
+        mask: Option<ByteMask>,
+        options: Options,
+    ) -> Result<Self, Error> {
+        // A secret embedded in a JPEG image can only have survived in its DCT coefficients
+        let image = Cover::open(&image_path, true)?;
 
-    pub fn save(&self, output: PathBuf) -> Result<(), Error> {
-        let mut secret = BufWriter::new(File::create(output)?);
-        let mut chunks = Vec::with_capacity(self.mask.chunks as usize);
-        let mut start = false;
-
-        for (i, b) in self.image.iter().map(|b| b & self.mask.mask).enumerate() {
-            // Secret starts when we find first non zero byte chunk
-            if !start && (b > 0) {
-                // The secret should start only at multiples of chunks. Add remaining offset if not the case.
-                let n = self.mask.chunks as usize;
-                let offset = (self.image.len() - i) % n;
-                if offset != 0 {
-                    (0..(n - offset)).for_each(|_| chunks.push(0));
-                }
-                start = true;
-            };
-
-            // Save chunk to buffer
-            if start {
-                chunks.push(b);
+        let mut samples: Vec<usize> = options
+            .channels
+            .indices(image.channels(), image.len())
+            .collect();
+        image.retain_alpha(&mut samples, 1);
+
+        // Bits given explicitly must be the ones stored in the preamble, along with the other parameters
+        let preamble_samples = scatter::preamble(&samples, options.scatter.as_deref())?;
+        let preamble = Self::read_preamble(&image, &preamble_samples)?;
+        if let Some(mask) = mask {
+            if mask.bits != preamble.bits || options.channel_bits != preamble.channels {
+                return Err(Error::BitsMismatch);
             }
+        }
+        if samples.len() < preamble.size() {
+            return Err(Error::InvalidHeader);
+        }
+        let taken = preamble_samples[..preamble.size()].to_vec();
+        scatter::skip(&mut samples, &taken);
+        image.retain_alpha(&mut samples, preamble.bits);
+
+        // The variances are computed from bits the payload does not change, so the same samples are selected
+        if let Some(texture) = preamble.texture {
+            let variances = image
+                .variances(ByteMask::new(preamble.bits)?.mask)
+                .map_err(|_| Error::InvalidHeader)?;
+            samples.retain(|&i| variances[i] > texture as f32);
+        }
 
-            // We can now recover the original byte from the chunks
-            if chunks.len() == chunks.capacity() {
-                // Recover original byte from LSB chunks
-                let byte = self.mask.join_chunks(&chunks);
+        // Chunks of payload bits are held by single samples, or by the syndromes of blocks of samples
+        let mask = match preamble.mode {
+            Mode::Lsb => ByteMask::new(preamble.bits)?,
+            Mode::Matrix(k) => ByteMask::new(k)?,
+            Mode::Stc(_) | Mode::Pvd(_) => ByteMask::new(1)?,
+        };
 
-                // Write recovered byte
-                secret.write_all(&[byte])?;
+        let (lanes, order) = scatter::layout(
+            samples,
+            image.channels(),
+            preamble.channels,
+            mask.bits,
+            &preamble.mode,
+            options.scatter.as_deref(),
+        )?;
 
-                // Reset the LSB byte chunks buffer
-                chunks.clear()
-            }
+        Ok(Decoder {
+            image,
+            mask,
+            mode: preamble.mode,
+            lanes,
+            order,
+            ecc: preamble.ecc,
+            corrected: Cell::new(0),
+            options,
+        })
+    }
+
+    /// Recovers the embedding parameters stored in the LSBs of the leading samples of the preamble order
+    fn read_preamble(image: &Cover, samples: &[usize]) -> Result<Preamble, Error> {
+        let preamble = Preamble::read_bits(samples.iter().map(|&i| image[i]))?;
+
+        if preamble.bits <= image.max_bits() {
+            Ok(preamble)
+        } else {
+            Err(Error::InvalidHeader)
         }
+    }
+
+    /// Iterator over the bytes recovered from the LSB chunks of the image, as embedded
+    fn raw_bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        let (image, mode, bits, max) = (&*self.image, self.mode, self.mask.bits, self.image.max());
+        let lanes = &self.lanes;
+        let columns = match mode {
+            Mode::Stc(width) => stc::columns(width),
+            _ => Vec::new(),
+        };
+
+        // The trellis state carries the syndrome of each block over to the following ones,
+        // PVD pairs hold a varying number of bits and none at all when out of range
+        let chunks = self
+            .order
+            .chunks_exact(mode.block_size())
+            .scan(0, move |state, block| {
+                let samples = block.iter().map(|&i| image[i]);
+                Some(match mode {
+                    Mode::Lsb => (image[block[0]], lanes[block[0] % lanes.len()]),
+                    Mode::Matrix(_) => (matrix::syndrome(samples), bits),
+                    Mode::Stc(_) => (stc::syndrome(state, &columns, samples), 1),
+                    Mode::Pvd(table) => table
+                        .extract((image[block[0]], image[block[1]]), max)
+                        .unwrap_or((0, 0)),
+                })
+            });
+        utils::join_chunks(chunks)
+    }
+
+    /// Iterator over the payload bytes, corrected by their Reed-Solomon codes if any
+    fn bytes(&self) -> Box<dyn Iterator<Item = u8> + '_> {
+        self.corrected.set(0);
+        match self.ecc {
+            Some(parity) => Box::new(ecc::decode(self.raw_bytes(), parity, &self.corrected)),
+            None => Box::new(self.raw_bytes()),
+        }
+    }
+
+    /// Number of corrupted payload bytes fixed by the last read, `None` without Reed-Solomon codes
+    pub fn corrected(&self) -> Option<usize> {
+        self.ecc.map(|_| self.corrected.get())
+    }
+
+    /// Recovers the secret and its metadata from the image
+    pub fn read(&self) -> Result<(Metadata, Vec<u8>), Error> {
+        payload::open(self.bytes(), &self.options)
+    }
+
+    /// Checks the signature of the embedded payload without recovering the secret
+    pub fn verify(&self, verifying_key: &VerifyingKey) -> Result<(), Error> {
+        payload::verify(self.bytes(), verifying_key)
+    }
+
+    /// Whether the image holds exactly the given payload, Reed-Solomon codes included
+    pub fn holds(&self, payload: &[u8]) -> bool {
+        self.raw_bytes()
+            .take(payload.len())
+            .eq(payload.iter().copied())
+    }
+
+    /// Writes the secret to the given file
+    pub fn save(&self, output: PathBuf) -> Result<(), Error> {
+        let (_, secret) = self.read()?;
+        Self::write(&secret, &File::create(output)?)
+    }
 
-        // Write remaining bytes
-        secret.flush()?;
+    /// Writes the secret into the given directory, restoring its original name and metadata
+    pub fn restore(&self, output_dir: PathBuf) -> Result<PathBuf, Error> {
+        let (metadata, secret) = self.read()?;
+        let path = metadata.path_in(&output_dir)?;
+
+        // The name comes from the image, so an existing file or symlink is never written through
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|error| match error.kind() {
+                std::io::ErrorKind::AlreadyExists => Error::OutputExists,
+                _ => error.into(),
+            })?;
+        Self::write(&secret, &file)?;
+        metadata.apply(&file)?;
+        Ok(path)
+    }
+
+    fn write(secret: &[u8], file: &File) -> Result<(), Error> {
+        let mut output = BufWriter::new(file);
+        output.write_all(secret)?;
+        output.flush()?;
         Ok(())
     }
 }
+
 #[cfg(test)]
 mod tests {
-    use super::{ByteMask, Decoder};
+    use super::{ByteMask, Decoder, Error, Options};
+    use crate::utils::Tmp;
     use std::path::PathBuf;
 
     #[test]
     fn test_new() {
         let mask = ByteMask::new(2).unwrap();
-        Decoder::new(PathBuf::from("./samples/the-matrix-reloaded.png"), mask).unwrap();
+        Decoder::new(
+            PathBuf::from("./samples/the-matrix-reloaded.png"),
+            Some(mask),
+            Options::default(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_new_detect_bits() {
+        let decoder = Decoder::new(
+            PathBuf::from("./samples/the-matrix-reloaded.png"),
+            None,
+            Options::default(),
+        )
+        .unwrap();
+        assert_eq!(decoder.mask.bits, 2);
     }
 
     #[test]
     fn test_save() {
+        let decoder = Decoder::new(
+            PathBuf::from("./samples/the-matrix-reloaded.png"),
+            None,
+            Options::default(),
+        )
+        .unwrap();
+        let mut tmp = Tmp::default();
+        let path = tmp.path("txt");
+        decoder.save(path.clone()).unwrap();
+        assert_eq!(
+            std::fs::read(path).unwrap(),
+            std::fs::read("./samples/secret.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let decoder = Decoder::new(
+            PathBuf::from("./samples/the-matrix-reloaded.png"),
+            None,
+            Options::default(),
+        )
+        .unwrap();
+        let mut tmp = Tmp::default();
+        let dir = tmp.dir();
+        let path = decoder.restore(dir.clone()).unwrap();
+        assert_eq!(path, dir.join("secret.txt"));
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            std::fs::read("./samples/secret.txt").unwrap()
+        );
+
+        // Existing files are never overwritten
+        std::fs::write(&path, "existing").unwrap();
+        assert!(matches!(decoder.restore(dir), Err(Error::OutputExists)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "existing");
+    }
+
+    #[test]
+    fn test_wrong_bits() {
+        let mask = ByteMask::new(3).unwrap();
+        assert!(matches!(
+            Decoder::new(
+                PathBuf::from("./samples/the-matrix-reloaded.png"),
+                Some(mask),
+                Options::default(),
+            ),
+            Err(Error::BitsMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_no_header() {
         let mask = ByteMask::new(2).unwrap();
-        let decoder =
-            Decoder::new(PathBuf::from("./samples/the-matrix-reloaded.png"), mask).unwrap();
-        decoder.save(PathBuf::from("./samples/tmp.txt")).unwrap();
-        std::fs::remove_file("./samples/tmp.txt").unwrap();
+        let mut tmp = Tmp::default();
+        let path = tmp.path("txt");
+        assert!(Decoder::new(
+            PathBuf::from("./samples/the-matrix.jpg"),
+            Some(mask),
+            Options::default(),
+        )
+        .and_then(|decoder| decoder.save(path.clone()))
+        .is_err());
+        assert!(!path.exists());
     }
 }
@@ -0,0 +1,283 @@
+use std::cell::Cell;
+
+/// Number of bytes of a codeword, data and parity, the largest over GF(2^8)
+pub const BLOCK_SIZE: usize = 255;
+
+/// Parity bytes per codeword when the redundancy is not given, correcting up to 16 corrupted bytes
+pub const DEFAULT_PARITY: u8 = 32;
+
+/// Fewest and most parity bytes per codeword, at least one error is corrected and one data byte held
+pub const MIN_PARITY: u8 = 2;
+pub const MAX_PARITY: u8 = 254;
+
+/// Logarithms and exponentials of the elements of GF(2^8), generated by the primitive polynomial
+/// x^8 + x^4 + x^3 + x^2 + 1. Exponentials are repeated so sums of two logarithms need no modulo
+const fn tables() -> ([u8; 512], [u8; 256]) {
+    let (mut exp, mut log) = ([0; 512], [0; 256]);
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    (exp, log)
+}
+
+const EXP: [u8; 512] = tables().0;
+const LOG: [u8; 256] = tables().1;
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+    }
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        0
+    } else {
+        EXP[LOG[a as usize] as usize + 255 - LOG[b as usize] as usize]
+    }
+}
+
+/// Powers of the generator element α
+fn alpha(power: usize) -> u8 {
+    EXP[power % 255]
+}
+
+/// Evaluates the polynomial, coefficients given lowest degree first
+fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |y, &c| mul(y, x) ^ c)
+}
+
+/// Generator polynomial of the code, highest degree first, with roots α^0 to α^(parity - 1)
+fn generator(parity: u8) -> Vec<u8> {
+    (0..parity as usize).fold(vec![1], |poly, i| {
+        let mut product = vec![0; poly.len() + 1];
+        for (j, &c) in poly.iter().enumerate() {
+            product[j] ^= c;
+            product[j + 1] ^= mul(c, alpha(i));
+        }
+        product
+    })
+}
+
+/// Splits the bytes into codewords of `BLOCK_SIZE` bytes, each holding `BLOCK_SIZE - parity` data bytes
+/// followed by their parity bytes. The last data block is zero padded
+pub fn encode(bytes: &[u8], parity: u8) -> Vec<u8> {
+    let generator = generator(parity);
+    let data_size = BLOCK_SIZE - parity as usize;
+
+    bytes
+        .chunks(data_size)
+        .flat_map(|data| {
+            let mut block = data.to_vec();
+            block.resize(BLOCK_SIZE, 0);
+
+            // The parity bytes are the remainder of the data divided by the generator polynomial
+            let mut remainder = block.clone();
+            for i in 0..data_size {
+                let coefficient = remainder[i];
+                if coefficient != 0 {
+                    for (j, &g) in generator.iter().enumerate().skip(1) {
+                        remainder[i + j] ^= mul(g, coefficient);
+                    }
+                }
+            }
+            block[data_size..].copy_from_slice(&remainder[data_size..]);
+            block
+        })
+        .collect()
+}
+
+/// Syndromes of a codeword, its evaluations at the roots of the generator polynomial, all zero if intact
+fn syndromes(block: &[u8], parity: u8) -> Vec<u8> {
+    (0..parity as usize)
+        .map(|i| block.iter().fold(0, |y, &c| mul(y, alpha(i)) ^ c))
+        .collect()
+}
+
+/// Corrects the codeword in place, returning the number of corrupted bytes fixed.
+/// `None` if there are more errors than half the parity bytes, the codeword is then left untouched
+pub fn correct(block: &mut [u8], parity: u8) -> Option<usize> {
+    let syndrome = syndromes(block, parity);
+    if syndrome.iter().all(|&s| s == 0) {
+        return Some(0);
+    }
+
+    // Berlekamp-Massey finds the error locator polynomial, lowest degree first
+    let (mut locator, mut previous) = (vec![1u8], vec![1u8]);
+    let (mut errors, mut shift, mut discrepancy) = (0, 1, 1);
+    for n in 0..syndrome.len() {
+        let delta = (1..=errors).fold(syndrome[n], |delta, i| {
+            delta ^ mul(*locator.get(i).unwrap_or(&0), syndrome[n - i])
+        });
+        if delta == 0 {
+            shift += 1;
+            continue;
+        }
+
+        let scale = div(delta, discrepancy);
+        let mut next = locator.clone();
+        next.resize(next.len().max(previous.len() + shift), 0);
+        for (i, &c) in previous.iter().enumerate() {
+            next[i + shift] ^= mul(scale, c);
+        }
+
+        if 2 * errors <= n {
+            previous = std::mem::replace(&mut locator, next);
+            errors = n + 1 - errors;
+            discrepancy = delta;
+            shift = 1;
+        } else {
+            locator = next;
+            shift += 1;
+        }
+    }
+    if 2 * errors > parity as usize {
+        return None;
+    }
+
+    // Chien search, the byte at position i has its error locator root at α^-(BLOCK_SIZE - 1 - i)
+    let positions: Vec<usize> = (0..block.len())
+        .filter(|&i| eval(&locator, alpha(255 - (BLOCK_SIZE - 1 - i) % 255)) == 0)
+        .collect();
+    if positions.len() != errors {
+        return None;
+    }
+
+    // Forney computes the error values from the evaluator polynomial and the locator derivative
+    let mut evaluator = vec![0; syndrome.len()];
+    for (i, &s) in syndrome.iter().enumerate() {
+        for (j, &l) in locator.iter().enumerate().take(syndrome.len() - i) {
+            evaluator[i + j] ^= mul(s, l);
+        }
+    }
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, &c)| if i % 2 == 1 { c } else { 0 })
+        .collect();
+
+    let mut corrected = block.to_vec();
+    for &i in &positions {
+        let x = alpha(BLOCK_SIZE - 1 - i);
+        let x_inverse = div(1, x);
+        let denominator = eval(&derivative, x_inverse);
+        if denominator == 0 {
+            return None;
+        }
+        corrected[i] ^= mul(x, div(eval(&evaluator, x_inverse), denominator));
+    }
+
+    // Too many errors may still be mistaken for fewer ones, which leave syndromes behind
+    if syndromes(&corrected, parity).iter().any(|&s| s != 0) {
+        return None;
+    }
+    block.copy_from_slice(&corrected);
+    Some(errors)
+}
+
+/// Iterator over the data bytes of the codewords read from the given bytes, corrected along the way.
+/// The number of bytes fixed is added to `corrected`, codewords beyond repair are passed through as read
+/// so the payload checksum rejects them
+pub fn decode<'a, I>(
+    bytes: I,
+    parity: u8,
+    corrected: &'a Cell<usize>,
+) -> impl Iterator<Item = u8> + 'a
+where
+    I: IntoIterator<Item = u8>,
+    I::IntoIter: 'a,
+{
+    let mut bytes = bytes.into_iter();
+    std::iter::from_fn(move || {
+        let mut block: Vec<u8> = bytes.by_ref().take(BLOCK_SIZE).collect();
+        if block.len() < BLOCK_SIZE {
+            return None;
+        }
+
+        if let Some(errors) = correct(&mut block, parity) {
+            corrected.set(corrected.get() + errors);
+        }
+        block.truncate(BLOCK_SIZE - parity as usize);
+        Some(block)
+    })
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{correct, decode, encode, syndromes, BLOCK_SIZE};
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_encode() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let encoded = encode(&bytes, 32);
+        assert_eq!(encoded.len(), 2 * BLOCK_SIZE);
+        assert_eq!(encoded[..223], bytes[..223]);
+        assert!(encoded
+            .chunks(BLOCK_SIZE)
+            .all(|block| syndromes(block, 32).iter().all(|&s| s == 0)));
+    }
+
+    #[test]
+    fn test_correct() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let bytes: Vec<u8> = (0..150).map(|_| rng.gen()).collect();
+
+        for &parity in &[2, 10, 32, 64] {
+            let encoded = encode(&bytes, parity);
+            for errors in 0..=parity as usize / 2 {
+                let mut block = encoded.clone();
+                for i in rand::seq::index::sample(&mut rng, BLOCK_SIZE, errors) {
+                    block[i] ^= rng.gen_range(1..=255);
+                }
+                assert_eq!(correct(&mut block, parity), Some(errors));
+                assert_eq!(block, encoded);
+            }
+        }
+
+        // Too many errors are detected, the codeword is left as it is
+        let encoded = encode(&bytes, 8);
+        let mut block = encoded.clone();
+        for byte in block.iter_mut().take(6) {
+            *byte ^= 0xff;
+        }
+        let corrupted = block.clone();
+        assert_eq!(correct(&mut block, 8), None);
+        assert_eq!(block, corrupted);
+    }
+
+    #[test]
+    fn test_decode() {
+        let bytes: Vec<u8> = (0..500).map(|i| i as u8).collect();
+        let mut encoded = encode(&bytes, 16);
+        encoded[3] ^= 1;
+        encoded[300] ^= 0x80;
+        encoded[301] = 0;
+        encoded.extend_from_slice(&[42; 100]);
+
+        let corrected = Cell::new(0);
+        let decoded: Vec<u8> = decode(encoded, 16, &corrected).collect();
+        assert_eq!(decoded.len(), 3 * (BLOCK_SIZE - 16));
+        assert_eq!(decoded[..bytes.len()], bytes[..]);
+        assert!(decoded[bytes.len()..].iter().all(|&b| b == 0));
+        assert_eq!(corrected.get(), 3);
+    }
+}
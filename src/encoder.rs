@@ -1,95 +1,640 @@
-use std::fs::File;
-use std::io::Read;
 use std::path::PathBuf;
 
+use crate::cover::Cover;
+use crate::ecc;
 use crate::errors::Error;
-use crate::utils::ByteMask;
-use image::{ImageBuffer, Rgb};
+use crate::matrix;
+use crate::options::Options;
+use crate::payload;
+use crate::preamble::{Mode, Preamble};
+use crate::scatter;
+use crate::stc;
+use crate::utils::{self, ByteMask};
+use image::ImageFormat;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 
 pub struct Encoder {
-    image: ImageBuffer<Rgb<u8>, Vec<u8>>,
-    secret: File,
+    image: Cover,
+    payload: Vec<u8>,
     mask: ByteMask,
-    zeroes: usize,
+    preamble: Preamble,
+    preamble_samples: Vec<usize>,
+    /// Bits per sample of each channel of the image layout, a single one unless given per channel
+    lanes: Vec<u8>,
+    order: Vec<usize>,
+    max: u16,
+    /// Generator of the noise filling the blocks not holding the payload, if enabled
+    noise: Option<ChaCha20Rng>,
+    matching: bool,
+    format: Option<ImageFormat>,
+    allow_lossy: bool,
 }
 
 impl Encoder {
-    pub fn new(image_path: PathBuf, secret_path: PathBuf, mask: ByteMask) -> Result<Self, Error> {
-        let image = image::open(image_path)?.to_rgb();
-        let secret = File::open(secret_path)?;
-        let metadata = secret.metadata()?;
+    pub fn new(
+        image_path: PathBuf,
+        secret_path: PathBuf,
+        mask: ByteMask,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let image = Cover::open(&image_path, options.dct)?;
+        // Palette indices keep their transparency as long as only their LSBs change
+        if (image.dct() || image.indexed()) && options.matching {
+            return Err(Error::UnsupportedMode);
+        }
+        if image.dct() && mask.bits > 1 {
+            return Err(Error::DctBits);
+        }
+        if mask.bits > image.max_bits() {
+            return Err(Error::InvalidNumberOfBits);
+        }
+        if options.texture.is_some() && options.matching {
+            // Matching may carry changes over to the bits the texture is computed from
+            return Err(Error::UnsupportedMode);
+        }
+
+        let mode = match (options.matrix, options.adaptive, options.pvd) {
+            (false, false, None) => Mode::Lsb,
+            // Parameters are only known once the preamble is taken, its size does not depend on them
+            (true, false, None) => Mode::Matrix(1),
+            (false, true, None) => Mode::Stc(1),
+            (false, false, Some(table)) => Mode::Pvd(table),
+            _ => return Err(Error::UnsupportedMode),
+        };
+        if mode != Mode::Lsb && mask.bits != 1 {
+            // Other modes work on the LSB of the samples, or on whole samples for PVD
+            return Err(Error::UnsupportedMode);
+        }
+        if options.channel_bits.is_some() && mode != Mode::Lsb {
+            return Err(Error::UnsupportedMode);
+        }
+        if let Mode::Pvd(_) = mode {
+            // Differences are moved within 8-bit ranges, changing more than the LSBs of the samples
+            if image.max() != u8::MAX as u16
+                || image.indexed()
+                || options.matching
+                || options.texture.is_some()
+            {
+                return Err(Error::UnsupportedMode);
+            }
+        }
+        let mut payload = payload::seal(&secret_path, options)?;
+        // The whole payload is protected, header included, the decoder reads the redundancy from the preamble
+        if let Some(parity) = options.ecc {
+            if !(ecc::MIN_PARITY..=ecc::MAX_PARITY).contains(&parity) {
+                return Err(Error::InvalidRedundancy);
+            }
+            payload = ecc::encode(&payload, parity);
+        }
+
+        let mut preamble = Preamble {
+            bits: mask.bits,
+            mode,
+            texture: options.texture,
+            channels: options.channel_bits,
+            ecc: options.ecc,
+        };
+
+        // Palette indices are only changed within entries of the same transparency, a bit for the preamble
+        let mut samples: Vec<usize> = options
+            .channels
+            .indices(image.channels(), image.len())
+            .collect();
+        image.retain_alpha(&mut samples, 1);
+        if samples.len() < preamble.size() {
+            return Err(Error::SecretTooLarge);
+        }
+        // Scattered along with the payload if a passphrase is given
+        let preamble_samples =
+            scatter::preamble(&samples, options.scatter.as_deref())?[..preamble.size()].to_vec();
+        scatter::skip(&mut samples, &preamble_samples);
+        image.retain_alpha(&mut samples, preamble.bits);
+
+        // Smooth areas are skipped, the capacity is reduced accordingly
+        if let Some(texture) = options.texture {
+            let variances = image.variances(mask.mask)?;
+            samples.retain(|&i| variances[i] > texture as f32);
+        }
+
+        let image_size = samples.len();
+        let mask = match preamble.mode {
+            Mode::Lsb => mask,
+            Mode::Matrix(_) => {
+                let k = matrix::parameter(payload.len() * 8, image_size)
+                    .ok_or(Error::SecretTooLarge)?;
+                preamble.mode = Mode::Matrix(k);
+                ByteMask::new(k)?
+            }
+            // Every payload bit gets a block of samples, as wide as the image allows
+            Mode::Stc(_) => {
+                let width = (image_size / (payload.len() * 8)).min(u16::MAX as usize);
+                if width == 0 {
+                    return Err(Error::SecretTooLarge);
+                }
+                preamble.mode = Mode::Stc(width as u16);
+                mask
+            }
+            Mode::Pvd(_) => mask,
+        };
 
-        let image_size = image.len();
-        let secret_size = (metadata.len() * mask.chunks as u64) as usize;
+        let (lanes, order) = scatter::layout(
+            samples,
+            image.channels(),
+            preamble.channels,
+            mask.bits,
+            &preamble.mode,
+            options.scatter.as_deref(),
+        )?;
+
+        let encoder = Encoder {
+            max: image.max(),
+            matching: options.matching,
+            image,
+            payload,
+            mask,
+            preamble,
+            preamble_samples,
+            lanes,
+            order,
+            noise: options
+                .noise
+                .then(|| scatter::noise(options.scatter.as_deref()))
+                .transpose()?,
+            format: options.format,
+            allow_lossy: options.allow_lossy,
+        };
 
-        if image_size < secret_size {
+        // Samples and PVD pairs may hold varying numbers of bits, they are counted over the image
+        let fits = match encoder.preamble.mode {
+            Mode::Lsb | Mode::Pvd(_) => encoder.capacity() >= encoder.payload_size(),
+            mode => {
+                encoder.mask.chunks(encoder.payload_size()) * mode.block_size()
+                    <= encoder.order.len()
+            }
+        };
+        if !fits {
             Err(Error::SecretTooLarge)
         } else {
-            let zeroes = image_size - secret_size;
+            Ok(encoder)
+        }
+    }
 
-            Ok(Encoder {
-                image,
-                secret,
-                mask,
-                zeroes,
-            })
+    /// Number of payload bytes the selected channels of the image can hold
+    pub fn capacity(&self) -> usize {
+        match self.preamble.mode {
+            Mode::Lsb => {
+                let bits: usize = self
+                    .order
+                    .iter()
+                    .map(|&i| self.lanes[i % self.lanes.len()] as usize)
+                    .sum();
+                bits / 8
+            }
+            Mode::Pvd(table) => {
+                let bits: usize = self
+                    .order
+                    .chunks_exact(2)
+                    .filter_map(|pair| {
+                        table.capacity((self.image[pair[0]], self.image[pair[1]]), self.max)
+                    })
+                    .map(usize::from)
+                    .sum();
+                bits / 8
+            }
+            mode => self.order.len() / mode.block_size() * self.mask.bits as usize / 8,
         }
     }
 
+    /// Number of payload bytes embedded in the image
+    pub fn payload_size(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Payload embedded in the image
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
     pub fn save(&mut self, output: PathBuf) -> Result<(), Error> {
-        let mut byte_iter = self.mask;
-        let mask = !byte_iter.mask;
+        // Checked before embedding anything, the format defaults to the output extension
+        let format = match self.format {
+            Some(format) => format,
+            None => ImageFormat::from_path(&output)?,
+        };
+        if self.image.lossy(format) && !self.allow_lossy {
+            return Err(Error::LossyFormat);
+        }
+
+        // Only adaptive embedding weighs the sample changes, by the texture of the original image
+        let costs = match self.preamble.mode {
+            Mode::Stc(_) => self.image.costs(),
+            _ => Vec::new(),
+        };
 
-        // Iterator over splitted secret bytes
-        let secret_bytes = self
-            .secret
-            .try_clone()?
-            .bytes()
-            .flat_map(|b| byte_iter.set_byte(b.unwrap()));
+        let image = &mut *self.image;
+        let mut rng = ChaCha20Rng::from_entropy();
 
-        // Fill secret with 0s at the beginning to fit full image and zip it with it
-        let image_secret_bytes = self
-            .image
-            .iter_mut()
-            .zip((0..self.zeroes).map(|_| 0).chain(secret_bytes));
+        // The preamble stores the embedding parameters using a single bit per sample
+        for (&i, b) in self.preamble_samples.iter().zip(self.preamble.to_bits()) {
+            image[i] = embed(image[i], b, 1, self.max, self.matching, &mut rng);
+        }
+
+        let (mode, mask, max, matching) =
+            (self.preamble.mode, self.mask.mask, self.max, self.matching);
+        let blocks = self.order.chunks_exact(mode.block_size());
+
+        // The payload splitted in chunks of bits, optionally followed by keyed noise
+        // filling the remaining blocks, so they look like the payload
+        let mut noise = self.noise.clone();
+        // Samples of each channel take chunks as wide as their bits, the other modes have a single lane
+        let lanes = &self.lanes;
+        let widths = self
+            .order
+            .iter()
+            .map(|&i| lanes[i % lanes.len()])
+            .chain(std::iter::repeat(self.mask.bits));
+        let mut chunks = utils::split_chunks(self.payload.iter().copied(), widths)
+            .chain(std::iter::from_fn(|| {
+                Some(noise.as_mut()?.next_u32() as u16 & mask)
+            }))
+            .peekable();
+
+        // Zip the chunks with the blocks of samples in order, the remaining image samples are left untouched
+        match mode {
+            Mode::Lsb => {
+                for (block, b) in blocks.zip(chunks) {
+                    let i = block[0];
+                    let mask = mask >> (self.mask.bits - lanes[i % lanes.len()]);
+                    image[i] = embed(image[i], b & mask, mask, max, matching, &mut rng);
+                }
+            }
+            // At most one sample of the block changes its LSB for its syndrome to hold the bits
+            Mode::Matrix(_) => {
+                for (block, b) in blocks.zip(chunks) {
+                    if let Some(position) = matrix::change(block.iter().map(|&i| image[i]), b) {
+                        let i = block[position];
+                        image[i] = embed(image[i], !image[i] & 1, 1, max, matching, &mut rng);
+                    }
+                }
+            }
+            // The trellis picks the cheapest samples to change for all the chunks at once
+            Mode::Stc(width) => {
+                let chunks: Vec<u16> = chunks.take(blocks.len()).collect();
+                let used = &self.order[..chunks.len() * width as usize];
+                let lsbs: Vec<u16> = used.iter().map(|&i| image[i] & 1).collect();
+                let costs: Vec<f32> = used.iter().map(|&i| costs[i]).collect();
+                let targets = stc::embed(&lsbs, &costs, &chunks, &stc::columns(width));
 
-        // Write the LSB bytes to the image
-        for (p, b) in image_secret_bytes {
-            *p = (*p & mask) | b;
+                for ((&i, lsb), target) in used.iter().zip(lsbs).zip(targets) {
+                    if lsb != target {
+                        image[i] = embed(image[i], target, 1, max, matching, &mut rng);
+                    }
+                }
+            }
+            // Each pair takes as many single bit chunks as its range allows, pairs out of range are skipped
+            Mode::Pvd(table) => {
+                for pair in blocks {
+                    if chunks.peek().is_none() {
+                        break;
+                    }
+                    let samples = (image[pair[0]], image[pair[1]]);
+                    if let Some(bits) = table.capacity(samples, max) {
+                        let value =
+                            (0..bits).fold(0, |value, _| value << 1 | chunks.next().unwrap_or(0));
+                        let (a, b) = table.embed(samples, value);
+                        image[pair[0]] = a;
+                        image[pair[1]] = b;
+                    }
+                }
+            }
         }
 
-        self.image.save(output)?;
+        self.image.save(&output, format)?;
         Ok(())
     }
 }
 
+/// Returns the image sample with its masked LSBs set to the given bits. In matching mode the sample
+/// is moved to the nearest value up to `max` holding those bits instead, picking randomly between the
+/// values above and below when both are as near, so the histogram keeps no pairs of values artefacts
+fn embed(
+    sample: u16,
+    bits: u16,
+    mask: u16,
+    max: u16,
+    matching: bool,
+    rng: &mut impl RngCore,
+) -> u16 {
+    let replaced = (sample & !mask) | bits;
+    if !matching || replaced == sample {
+        return replaced;
+    }
+
+    // Values holding the bits are a mask + 1 step apart, those out of the sample range are skipped
+    let step = mask as i32 + 1;
+    let distance = |value: i32| (value - sample as i32).abs();
+    let candidates = [replaced as i32 - step, replaced as i32 + step]
+        .iter()
+        .copied()
+        .filter(|value| (0..=max as i32).contains(value))
+        .fold(vec![replaced as i32], |mut nearest, value| {
+            match distance(value).cmp(&distance(nearest[0])) {
+                std::cmp::Ordering::Less => nearest = vec![value],
+                std::cmp::Ordering::Equal => nearest.push(value),
+                std::cmp::Ordering::Greater => (),
+            }
+            nearest
+        });
+
+    candidates[rng.next_u32() as usize % candidates.len()] as u16
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{ByteMask, Encoder};
-    use std::path::PathBuf;
+    use super::{embed, ByteMask, Encoder, Mode, Options};
+    use crate::cover::Cover;
+    use crate::decoder::Decoder;
+    use crate::errors::Error;
+    use crate::header::Header;
+    use crate::pvd::Table;
+    use crate::utils::Tmp;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
 
-    #[test]
-    fn test_new() {
-        let mask = ByteMask::new(2).unwrap();
-        let encoder = Encoder::new(
+    fn new(options: &Options, bits: u8) -> Result<Encoder, Error> {
+        Encoder::new(
             PathBuf::from("./samples/the-matrix.jpg"),
             PathBuf::from("./samples/secret.txt"),
-            mask,
+            ByteMask::new(bits).unwrap(),
+            options,
         )
-        .unwrap();
-        assert_eq!(encoder.zeroes, 417520);
+    }
+
+    /// Embeds the sample secret and saves it to a file of its own, removed even if a check fails.
+    /// Returns the original samples along with the encoder, whose image holds the embedded ones
+    fn roundtrip(options: Options, bits: u8) -> (Vec<u16>, Encoder) {
+        let mut tmp = Tmp::default();
+        let path = tmp.path("png");
+
+        let mut encoder = new(&options, bits).unwrap();
+        let original = encoder.image.to_vec();
+        let saved = encoder.save(path.clone());
+        let decoder = Decoder::new(
+            path.clone(),
+            None,
+            Options {
+                scatter: options.scatter,
+                channels: options.channels,
+                ..Options::default()
+            },
+        );
+
+        saved.unwrap();
+        assert!(decoder.unwrap().holds(encoder.payload()));
+        (original, encoder)
+    }
+
+    fn cover() -> Cover {
+        Cover::open(Path::new("./samples/the-matrix.jpg"), false).unwrap()
+    }
+
+    /// Number of samples changed by the embedding
+    fn changes(original: &[u16], encoder: &Encoder) -> usize {
+        original
+            .iter()
+            .zip(encoder.image.iter())
+            .filter(|(a, b)| a != b)
+            .count()
+    }
+
+    #[test]
+    fn test_new() {
+        let encoder = new(&Options::default(), 2).unwrap();
+        let payload_size = Header::SIZE + 22 + "secret.txt".len() + 20;
+        assert_eq!(encoder.payload.len(), payload_size);
+        assert_eq!(encoder.order.len(), 417600 - encoder.preamble.size());
+        assert_eq!(encoder.capacity(), (417600 - encoder.preamble.size()) / 4);
+    }
+
+    #[test]
+    fn test_capacity_channels() {
+        let channels = Options {
+            channels: "gb".parse().unwrap(),
+            ..Options::default()
+        };
+        let encoder = new(&channels, 2).unwrap();
+        assert_eq!(
+            encoder.capacity(),
+            (417600 / 3 * 2 - encoder.preamble.size()) / 4
+        );
+        assert!(encoder.order.iter().all(|i| i % 3 != 0));
+        roundtrip(channels, 2);
     }
 
     #[test]
     fn test_save() {
+        roundtrip(Options::default(), 2);
+    }
+
+    #[test]
+    fn test_scatter_preamble() {
+        let (_, encoder) = roundtrip(
+            Options {
+                scatter: Some(String::from("white rabbit")),
+                ..Options::default()
+            },
+            1,
+        );
+
+        // The preamble is not held by the first samples, nor by the ones holding the payload
+        let size = encoder.preamble.size();
+        assert_ne!(encoder.preamble_samples, (0..size).collect::<Vec<_>>());
+        assert!(encoder.preamble_samples.iter().any(|&i| i >= size));
+        assert!(encoder
+            .order
+            .iter()
+            .all(|i| !encoder.preamble_samples.contains(i)));
+    }
+
+    #[test]
+    fn test_unused_untouched() {
+        // Everything after the payload keeps the original bytes, unless filled with noise
+        for &noise in &[false, true] {
+            let (original, encoder) = roundtrip(
+                Options {
+                    noise,
+                    ..Options::default()
+                },
+                2,
+            );
+            let used = encoder.preamble.size() + encoder.mask.chunks(encoder.payload.len());
+            assert_eq!(original[used..] == encoder.image[used..], !noise);
+        }
+    }
+
+    #[test]
+    fn test_matrix() {
+        let matrix = |matrix| Options {
+            matrix,
+            ..Options::default()
+        };
+
+        // A small secret in a large image gets the largest code fitting in it
+        let (original, encoder) = roundtrip(matrix(true), 1);
+        let bits = encoder.payload.len() * 8;
+        let k = match encoder.preamble.mode {
+            Mode::Matrix(k) => k,
+            _ => panic!("matrix embedding expected"),
+        };
+        assert!(bits.div_ceil(k as usize) * ((1 << k) - 1) <= encoder.order.len());
+        assert!(bits.div_ceil(k as usize + 1) * ((1 << (k + 1)) - 1) > encoder.order.len());
+        assert!(encoder.capacity() >= encoder.payload_size());
+
+        // Far fewer samples change than with LSB embedding
+        let matrix_changes = changes(&original, &encoder);
+        let (original, lsb) = roundtrip(matrix(false), 1);
+        assert!(matrix_changes <= bits.div_ceil(k as usize) + encoder.preamble.size());
+        assert!(matrix_changes * 2 < changes(&original, &lsb));
+
+        // Hamming codes only use the LSB
+        assert!(new(&matrix(true), 2).is_err());
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let adaptive = |adaptive| Options {
+            adaptive,
+            ..Options::default()
+        };
+
+        // Average cost in the original image of the changed payload samples
+        let cost = |original: &[u16], encoder: &Encoder| {
+            let costs = cover().costs();
+            let changed: Vec<usize> = (0..original.len())
+                .filter(|&i| original[i] != encoder.image[i])
+                .filter(|i| !encoder.preamble_samples.contains(i))
+                .collect();
+            changed.iter().map(|&i| costs[i]).sum::<f32>() / changed.len() as f32
+        };
+
+        let (original, encoder) = roundtrip(adaptive(true), 1);
+        let width = match encoder.preamble.mode {
+            Mode::Stc(width) => width as usize,
+            _ => panic!("adaptive embedding expected"),
+        };
+        assert_eq!(width, encoder.order.len() / (encoder.payload.len() * 8));
+
+        // Fewer samples change, in more textured areas
+        let (lsb_original, lsb) = roundtrip(adaptive(false), 1);
+        assert!(changes(&original, &encoder) < changes(&lsb_original, &lsb));
+        assert!(cost(&original, &encoder) * 2.0 < cost(&lsb_original, &lsb));
+    }
+
+    #[test]
+    fn test_pvd() {
+        let pvd = |pvd, matrix| Options {
+            pvd,
+            matrix,
+            ..Options::default()
+        };
+
+        // Pairs of contrasting samples hold several bits, so PVD fits more than one bit per sample
+        let (original, encoder) = roundtrip(pvd(Some(Table::default()), false), 1);
+        assert!(encoder.capacity() > new(&pvd(None, false), 1).unwrap().capacity());
+
+        // Every pair keeps the average of its samples
+        let average = |image: &[u16], pair: &[usize]| (image[pair[0]] + image[pair[1]]) / 2;
+        assert!(encoder
+            .order
+            .chunks_exact(2)
+            .all(|pair| average(&original, pair) == average(&encoder.image, pair)));
+
+        // A single embedding mode at a time
+        assert!(new(&pvd(Some(Table::default()), true), 1).is_err());
+    }
+
+    #[test]
+    fn test_texture() {
+        let texture = |texture, matching| Options {
+            texture,
+            matching,
+            noise: true,
+            ..Options::default()
+        };
+
+        // Only textured samples are used, reducing the capacity
+        let (original, encoder) = roundtrip(texture(Some(200), false), 2);
+        let variances = cover().variances(0b11).unwrap();
+        assert!(encoder.order.iter().all(|&i| variances[i] > 200.0));
+        assert!(encoder.capacity() < new(&texture(None, false), 2).unwrap().capacity());
+
+        assert!((0..original.len())
+            .filter(|&i| original[i] != encoder.image[i])
+            .filter(|i| !encoder.preamble_samples.contains(i))
+            .all(|i| variances[i] > 200.0));
+
+        // Matching could change the bits the selection is computed from
+        assert!(new(&texture(Some(200), true), 2).is_err());
+    }
+
+    #[test]
+    fn test_embed_matching() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+        // Replacing only ever changes the masked bits
+        assert_eq!(embed(0b1010, 1, 1, 255, false, &mut rng), 0b1011);
+        assert_eq!(embed(0b1011, 2, 3, 255, false, &mut rng), 0b1010);
+
+        // Matching moves by one in either direction, but never out of the byte range
+        let values: HashSet<u16> = (0..64)
+            .map(|_| embed(10, 1, 1, 255, true, &mut rng))
+            .collect();
+        assert_eq!(values, [9, 11].iter().copied().collect());
+        assert_eq!(embed(0, 1, 1, 255, true, &mut rng), 1);
+        assert_eq!(embed(255, 0, 1, 255, true, &mut rng), 254);
+        assert_eq!(embed(10, 0, 1, 255, true, &mut rng), 10);
+
+        // With more bits the nearest value holding them is picked
+        assert_eq!(embed(0b1011, 0b00, 3, 255, true, &mut rng), 0b1100);
+        assert_eq!(embed(0b1000, 0b11, 3, 255, true, &mut rng), 0b0111);
+        assert_eq!(embed(1, 0b11, 3, 255, true, &mut rng), 3);
+        assert_eq!(embed(254, 0b00, 3, 255, true, &mut rng), 252);
+        assert_eq!(embed(200, 7, 255, 255, true, &mut rng), 7);
+
+        // 16-bit samples are only clamped at their own range
+        assert_eq!(embed(255, 0, 1, u16::MAX, true, &mut rng) % 2, 0);
+        assert_eq!(embed(u16::MAX, 0, 1, u16::MAX, true, &mut rng), 65534);
+        assert_eq!(
+            embed(0x1234, 0xfff, 0xfff, u16::MAX, false, &mut rng),
+            0x1fff
+        );
+    }
+
+    #[test]
+    fn test_compressed_fits() {
+        // Larger than the image capacity, but highly compressible
+        let mut tmp = Tmp::default();
+        let secret = tmp.path("txt");
+        std::fs::write(&secret, vec![b'A'; 200_000]).unwrap();
+
         let mask = ByteMask::new(2).unwrap();
-        let mut encoder = Encoder::new(
-            PathBuf::from("./samples/the-matrix.jpg"),
-            PathBuf::from("./samples/secret.txt"),
-            mask,
-        )
-        .unwrap();
-        encoder.save(PathBuf::from("./samples/tmp.png")).unwrap();
-        std::fs::remove_file("./samples/tmp.png").unwrap();
+        let new = |compress| {
+            Encoder::new(
+                PathBuf::from("./samples/the-matrix.jpg"),
+                secret.clone(),
+                mask,
+                &Options {
+                    compress,
+                    ..Options::default()
+                },
+            )
+        };
+        let (uncompressed, compressed) = (new(false), new(true));
+
+        assert!(uncompressed.is_err());
+        assert!(compressed.is_ok());
     }
 }
@@ -1,9 +1,25 @@
-#[derive(Debug)]
 pub enum Error {
-    SecretReadError,
+    SecretRead,
     SecretTooLarge,
     InvalidNumberOfBits,
-    ImageReadWriteError,
+    ImageReadWrite,
+    InvalidHeader,
+    CorruptedSecret,
+    UnsafeFileName,
+    WrongPassword,
+    WrongIdentity,
+    InvalidKey,
+    InvalidSignature,
+    InvalidChannels,
+    UnsupportedImage,
+    UnsupportedMode,
+    LossyFormat,
+    VerificationFailed,
+    InvalidTable,
+    InvalidRedundancy,
+    OutputExists,
+    BitsMismatch,
+    DctBits,
 }
 
 impl std::error::Error for Error {}
@@ -11,24 +27,82 @@ impl std::error::Error for Error {}
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
-            Error::SecretReadError => write!(f, "Something when wrong while reading secret file"),
+            Error::SecretRead => write!(f, "Something when wrong while reading secret file"),
             Error::SecretTooLarge => write!(f, "Secret is too large to fit in image"),
-            Error::InvalidNumberOfBits => write!(f, "Only 1 to 8 LSB bits are allowed"),
-            Error::ImageReadWriteError => {
+            Error::InvalidNumberOfBits => write!(
+                f,
+                "Only 1 to 8 LSB bits are allowed, up to 16 for 16-bit images and fewer for small palettes"
+            ),
+            Error::ImageReadWrite => {
                 write!(f, "Something went wrong while processing the image")
             }
+            Error::InvalidHeader => write!(f, "No valid secret found in image"),
+            Error::CorruptedSecret => write!(
+                f,
+                "Secret is corrupted or was encoded with different settings"
+            ),
+            Error::UnsafeFileName => write!(f, "Secret has no usable file name"),
+            Error::WrongPassword => write!(f, "Secret is encrypted, wrong or missing password"),
+            Error::WrongIdentity => write!(
+                f,
+                "Secret is encrypted to other recipients, wrong or missing identity"
+            ),
+            Error::InvalidKey => write!(f, "Invalid key or key file"),
+            Error::InvalidSignature => write!(f, "Secret signature is missing or invalid"),
+            Error::InvalidChannels => {
+                write!(f, "Channels must be a combination of r, g, b and a")
+            }
+            Error::UnsupportedImage => write!(
+                f,
+                "Image is not supported, animated or saved in a format unable to hold it"
+            ),
+            Error::UnsupportedMode => write!(f, "Embedding mode is not supported by this image"),
+            Error::LossyFormat => write!(
+                f,
+                "Output format is lossy and would destroy the secret, use --allow-lossy to save anyway"
+            ),
+            Error::VerificationFailed => {
+                write!(f, "Secret could not be recovered from the saved image, which was removed")
+            }
+            Error::InvalidTable => write!(
+                f,
+                "Range table must be comma separated powers of two adding up to 256"
+            ),
+            Error::InvalidRedundancy => write!(
+                f,
+                "Redundancy must be 2 to 254 parity bytes per block of 255 bytes"
+            ),
+            Error::OutputExists => write!(
+                f,
+                "Output file already exists, it is never overwritten"
+            ),
+            Error::BitsMismatch => write!(
+                f,
+                "Number of bits differs from the one the secret was embedded with"
+            ),
+            Error::DctBits => write!(
+                f,
+                "JPEG outputs hold a single bit per DCT coefficient, more would change its size category"
+            ),
         }
     }
 }
 
+/// Errors returned from `main` are printed with `Debug`, so they read the same as their description
+impl std::fmt::Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(_: std::io::Error) -> Self {
-        Error::SecretReadError
+        Error::SecretRead
     }
 }
 
 impl From<image::ImageError> for Error {
     fn from(_: image::ImageError) -> Self {
-        Error::ImageReadWriteError
+        Error::ImageReadWrite
     }
 }
@@ -0,0 +1,205 @@
+use std::convert::TryInto;
+
+use crate::crypto::{Encryption, Stanza, NONCE_SIZE, SALT_SIZE};
+use crate::errors::Error;
+
+/// Identifies the start of a PISS payload
+pub const MAGIC: [u8; 4] = *b"PISS";
+
+/// Version of the payload format written by this build
+pub const VERSION: u8 = 1;
+
+/// Header flag set when the secret is encrypted with a password
+const FLAG_PASSWORD: u8 = 0b0000_0001;
+/// Header flag set when the secret is encrypted to a set of recipients
+const FLAG_RECIPIENTS: u8 = 0b0000_0010;
+/// Header flag set when an Ed25519 signature follows the secret
+const FLAG_SIGNED: u8 = 0b0000_0100;
+/// Header flag set when the secret and its metadata are deflate compressed
+const FLAG_COMPRESSED: u8 = 0b0000_1000;
+
+/// Describes the payload embedded in an image, it is written in front of the secret
+/// so the decoder knows where the secret ends, whether it is intact and how to open it
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    pub length: u32,
+    pub checksum: u32,
+    pub encryption: Option<Encryption>,
+    pub signed: bool,
+    pub compressed: bool,
+}
+
+impl Header {
+    /// Number of bytes the fixed part of the header takes once serialized
+    pub const SIZE: usize = 14;
+
+    pub fn new(secret: &[u8]) -> Result<Self, Error> {
+        let length = secret.len().try_into().map_err(|_| Error::SecretTooLarge)?;
+        let checksum = crc32fast::hash(secret);
+        Ok(Header {
+            length,
+            checksum,
+            encryption: None,
+            signed: false,
+            compressed: false,
+        })
+    }
+
+    /// Checks the recovered secret against the length and checksum in the header
+    pub fn verify(&self, secret: &[u8]) -> Result<(), Error> {
+        if secret.len() != self.length as usize || crc32fast::hash(secret) != self.checksum {
+            Err(Error::CorruptedSecret)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        match self.encryption {
+            Some(Encryption::Password { .. }) => flags |= FLAG_PASSWORD,
+            Some(Encryption::Recipients { .. }) => flags |= FLAG_RECIPIENTS,
+            None => (),
+        }
+        if self.signed {
+            flags |= FLAG_SIGNED;
+        }
+        if self.compressed {
+            flags |= FLAG_COMPRESSED;
+        }
+        flags
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.flags());
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes.extend_from_slice(&self.checksum.to_be_bytes());
+        if let Some(encryption) = &self.encryption {
+            bytes.append(&mut encryption.to_bytes());
+        }
+        bytes
+    }
+
+    /// Bytes authenticated along with an encrypted secret: the whole header but the checksum,
+    /// which is computed from the ciphertext
+    pub fn associated_data(&self) -> Vec<u8> {
+        let mut bytes = self.to_bytes();
+        bytes.drain(10..Self::SIZE);
+        bytes
+    }
+
+    /// Parses the header from the given bytes, fails if they do not start with a valid header.
+    /// Only the bytes belonging to the header are consumed
+    pub fn read<I: Iterator<Item = u8>>(bytes: &mut I) -> Result<Self, Error> {
+        let fixed = take(bytes, Self::SIZE)?;
+        if fixed[..4] != MAGIC || fixed[4] != VERSION {
+            return Err(Error::InvalidHeader);
+        }
+
+        let flags = fixed[5];
+        let length = u32::from_be_bytes(fixed[6..10].try_into().unwrap());
+        let checksum = u32::from_be_bytes(fixed[10..14].try_into().unwrap());
+
+        let encryption = if flags & FLAG_PASSWORD != 0 {
+            let parameters = take(bytes, SALT_SIZE + NONCE_SIZE)?;
+            Some(Encryption::password_from_bytes(&parameters))
+        } else if flags & FLAG_RECIPIENTS != 0 {
+            let mut parameters = take(bytes, 1)?;
+            let size = NONCE_SIZE + parameters[0] as usize * Stanza::SIZE;
+            parameters.append(&mut take(bytes, size)?);
+            Some(Encryption::recipients_from_bytes(&parameters))
+        } else {
+            None
+        };
+
+        Ok(Header {
+            length,
+            checksum,
+            encryption,
+            signed: flags & FLAG_SIGNED != 0,
+            compressed: flags & FLAG_COMPRESSED != 0,
+        })
+    }
+}
+
+/// Takes exactly `n` bytes from the iterator
+fn take<I: Iterator<Item = u8>>(bytes: &mut I, n: usize) -> Result<Vec<u8>, Error> {
+    let taken: Vec<u8> = bytes.take(n).collect();
+    if taken.len() == n {
+        Ok(taken)
+    } else {
+        Err(Error::InvalidHeader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Encryption, Header, MAGIC, NONCE_SIZE, SALT_SIZE, VERSION};
+    use rand::rngs::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    #[test]
+    fn test_roundtrip() {
+        let header = Header::new(b"The Matrix has you.").unwrap();
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), Header::SIZE);
+        assert_eq!(Header::read(&mut bytes.into_iter()).unwrap(), header);
+    }
+
+    #[test]
+    fn test_roundtrip_encryption() {
+        let mut header = Header::new(b"The Matrix has you.").unwrap();
+        header.encryption = Some(Encryption::password("red pill").unwrap().0);
+
+        let mut bytes = header.to_bytes();
+        assert_eq!(bytes.len(), Header::SIZE + SALT_SIZE + NONCE_SIZE);
+
+        // Bytes after the header are left untouched
+        bytes.push(42);
+        let mut bytes = bytes.into_iter();
+        assert_eq!(Header::read(&mut bytes).unwrap(), header);
+        assert_eq!(bytes.next(), Some(42));
+    }
+
+    #[test]
+    fn test_roundtrip_recipients() {
+        let recipients = [
+            PublicKey::from(&StaticSecret::random_from_rng(OsRng)),
+            PublicKey::from(&StaticSecret::random_from_rng(OsRng)),
+        ];
+
+        let mut header = Header::new(b"The Matrix has you.").unwrap();
+        header.encryption = Some(Encryption::recipients(&recipients).unwrap().0);
+        header.signed = true;
+        header.compressed = true;
+
+        let bytes = header.to_bytes();
+        assert_eq!(Header::read(&mut bytes.into_iter()).unwrap(), header);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let read = |bytes: &[u8]| Header::read(&mut bytes.iter().copied());
+        assert!(read(&[0; Header::SIZE]).is_err());
+        assert!(read(&MAGIC).is_err());
+
+        let mut bytes = Header::new(&[1]).unwrap().to_bytes();
+        bytes[4] = VERSION + 1;
+        assert!(read(&bytes).is_err());
+
+        let mut bytes = Header::new(&[1]).unwrap().to_bytes();
+        bytes[5] = 1;
+        assert!(read(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let header = Header::new(b"The Matrix has you.").unwrap();
+        assert!(header.verify(b"The Matrix has you.").is_ok());
+        assert!(header.verify(b"The Matrix has yov.").is_err());
+        assert!(header.verify(b"The Matrix has you").is_err());
+    }
+}
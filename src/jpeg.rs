@@ -0,0 +1,664 @@
+use std::fs;
+use std::path::Path;
+
+use crate::errors::Error;
+
+/// Markers of the JPEG segments that are interpreted, any other segment is copied as it is
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOS: u8 = 0xda;
+const DHT: u8 = 0xc4;
+const DRI: u8 = 0xdd;
+const DAC: u8 = 0xcc;
+const RST0: u8 = 0xd0;
+const RST7: u8 = 0xd7;
+
+/// Number of coefficients of a block
+const BLOCK_SIZE: usize = 64;
+
+/// Smallest magnitude of the coefficients holding the payload. Setting the LSB of a magnitude
+/// of at least 2 keeps it within its Huffman size category and never turns it into a 0 or a 1,
+/// so the same coefficients are found when decoding and the original tables still encode them
+const MIN_MAGNITUDE: u16 = 2;
+
+/// Huffman table of a baseline JPEG, as defined by a DHT segment
+#[derive(Clone)]
+struct Huffman {
+    counts: [u8; 17],
+    symbols: Vec<u8>,
+    max_code: [i32; 18],
+    min_code: [i32; 17],
+    offsets: [usize; 17],
+    codes: Vec<Option<(u16, u8)>>,
+}
+
+impl Huffman {
+    /// Builds the canonical codes from the number of codes of each length and their symbols
+    fn new(counts: [u8; 17], symbols: Vec<u8>) -> Self {
+        let mut table = Huffman {
+            counts,
+            symbols,
+            max_code: [-1; 18],
+            min_code: [0; 17],
+            offsets: [0; 17],
+            codes: vec![None; 256],
+        };
+
+        let (mut code, mut k) = (0, 0);
+        for (len, &count) in counts.iter().enumerate().skip(1) {
+            table.offsets[len] = k;
+            table.min_code[len] = code;
+            for _ in 0..count {
+                if let Some(&symbol) = table.symbols.get(k) {
+                    table.codes[symbol as usize] = Some((code as u16, len as u8));
+                }
+                code += 1;
+                k += 1;
+            }
+            if count > 0 {
+                table.max_code[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, Error> {
+        let mut code = 0;
+        for len in 1..=16 {
+            code = (code << 1) | reader.bit()? as i32;
+            if self.counts[len] > 0 && code <= self.max_code[len] {
+                let k = self.offsets[len] + (code - self.min_code[len]) as usize;
+                return self.symbols.get(k).copied().ok_or(Error::ImageReadWrite);
+            }
+        }
+        Err(Error::ImageReadWrite)
+    }
+
+    fn encode(&self, writer: &mut BitWriter, symbol: u8) -> Result<(), Error> {
+        let (code, len) = self.codes[symbol as usize].ok_or(Error::ImageReadWrite)?;
+        writer.bits(code, len);
+        Ok(())
+    }
+}
+
+/// Reads the bits of an entropy coded interval, byte stuffing already removed
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<u16, Error> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or(Error::ImageReadWrite)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit as u16)
+    }
+
+    fn bits(&mut self, len: u8) -> Result<u16, Error> {
+        (0..len).try_fold(0, |value, _| Ok((value << 1) | self.bit()?))
+    }
+}
+
+/// Writes the bits of an entropy coded interval, stuffing a zero byte after every 0xff
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    byte: u8,
+    len: u8,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u16, len: u8) {
+        for i in (0..len).rev() {
+            self.byte = (self.byte << 1) | ((value >> i) & 1) as u8;
+            self.len += 1;
+            if self.len == 8 {
+                self.bytes.push(self.byte);
+                if self.byte == 0xff {
+                    self.bytes.push(0);
+                }
+                self.byte = 0;
+                self.len = 0;
+            }
+        }
+    }
+
+    /// Pads the last byte with ones
+    fn flush(&mut self) {
+        if self.len > 0 {
+            self.bits(0xff, 8 - self.len);
+        }
+    }
+}
+
+/// Number of bits of the magnitude of a coefficient, its Huffman size category
+fn category(value: i16) -> u8 {
+    (16 - value.unsigned_abs().leading_zeros()) as u8
+}
+
+/// Bits written after the Huffman code of a coefficient, negative values are stored minus one
+fn magnitude_bits(value: i16, size: u8) -> u16 {
+    let bits = if value < 0 { value - 1 } else { value };
+    (bits as u16) & ((1u32 << size) - 1) as u16
+}
+
+/// Inverse of `magnitude_bits`
+fn extend(bits: u16, size: u8) -> i16 {
+    if size == 0 {
+        0
+    } else if bits < 1 << (size - 1) {
+        bits as i16 - (1 << size) as i16 + 1
+    } else {
+        bits as i16
+    }
+}
+
+/// Colour component of the frame, its blocks of quantised coefficients in zigzag order
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    blocks_w: usize,
+    blocks: Vec<[i16; BLOCK_SIZE]>,
+}
+
+/// Component of a scan together with the Huffman tables it is coded with
+struct ScanComponent {
+    index: usize,
+    dc: Huffman,
+    ac: Huffman,
+}
+
+/// Entropy coded scan, its header is kept with the raw segments in front of it
+struct Scan {
+    components: Vec<ScanComponent>,
+    restart_interval: usize,
+}
+
+/// Part of the file, either copied as it is or coded from the coefficients when saved
+enum Part {
+    Raw(Vec<u8>),
+    Scan(Scan),
+}
+
+/// Baseline JPEG image decoded down to its quantised DCT coefficients, which can be
+/// modified and encoded back with the original tables without recompressing the pixels
+pub struct Jpeg {
+    width: usize,
+    height: usize,
+    h_max: usize,
+    v_max: usize,
+    components: Vec<Component>,
+    parts: Vec<Part>,
+}
+
+impl Jpeg {
+    /// Opens a JPEG image, returns `None` for any other image
+    pub fn open(path: &Path) -> Result<Option<Self>, Error> {
+        let bytes = fs::read(path)?;
+        if !bytes.starts_with(&[0xff, SOI]) {
+            return Ok(None);
+        }
+        Self::parse(&bytes).map(Some)
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let mut jpeg = Jpeg {
+            width: 0,
+            height: 0,
+            h_max: 1,
+            v_max: 1,
+            components: Vec::new(),
+            parts: Vec::new(),
+        };
+        let mut dc_tables: [Option<Huffman>; 4] = Default::default();
+        let mut ac_tables: [Option<Huffman>; 4] = Default::default();
+        let mut restart_interval = 0;
+
+        let mut raw = vec![0xff, SOI];
+        let mut position = 2;
+        loop {
+            let marker = match bytes.get(position..position + 2) {
+                Some(&[0xff, marker]) => marker,
+                _ => return Err(Error::ImageReadWrite),
+            };
+
+            // Fill bytes in front of a marker
+            if marker == 0xff {
+                raw.push(0xff);
+                position += 1;
+                continue;
+            }
+
+            if marker == EOI {
+                raw.extend_from_slice(&bytes[position..]);
+                break;
+            }
+
+            let length = bytes
+                .get(position + 2..position + 4)
+                .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+                .filter(|&length| length >= 2)
+                .ok_or(Error::ImageReadWrite)?;
+            let segment = bytes
+                .get(position..position + 2 + length)
+                .ok_or(Error::ImageReadWrite)?;
+            let data = &segment[4..];
+            raw.extend_from_slice(segment);
+            position += segment.len();
+
+            match marker {
+                DHT => Self::parse_tables(data, &mut dc_tables, &mut ac_tables)?,
+                DRI if data.len() >= 2 => {
+                    restart_interval = u16::from_be_bytes([data[0], data[1]]) as usize
+                }
+                0xc0 | 0xc1 => jpeg.parse_frame(data)?,
+                // Progressive, lossless and arithmetic coded images
+                0xc2 | 0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf | DAC => {
+                    return Err(Error::UnsupportedImage)
+                }
+                SOS => {
+                    let scan = jpeg.parse_scan(data, &dc_tables, &ac_tables, restart_interval)?;
+                    jpeg.parts.push(Part::Raw(std::mem::take(&mut raw)));
+                    position += jpeg.decode_scan(&scan, &bytes[position..])?;
+                    jpeg.parts.push(Part::Scan(scan));
+                }
+                _ => (),
+            }
+        }
+
+        jpeg.parts.push(Part::Raw(raw));
+        Ok(jpeg)
+    }
+
+    fn parse_tables(
+        mut data: &[u8],
+        dc_tables: &mut [Option<Huffman>; 4],
+        ac_tables: &mut [Option<Huffman>; 4],
+    ) -> Result<(), Error> {
+        while !data.is_empty() {
+            let (class, id) = (data[0] >> 4, (data[0] & 0x0f) as usize);
+            let mut counts = [0; 17];
+            counts[1..].copy_from_slice(data.get(1..17).ok_or(Error::ImageReadWrite)?);
+
+            let total: usize = counts.iter().map(|&c| c as usize).sum();
+            let symbols = data.get(17..17 + total).ok_or(Error::ImageReadWrite)?;
+            let table = Some(Huffman::new(counts, symbols.to_vec()));
+
+            match (class, id) {
+                (0, 0..=3) => dc_tables[id] = table,
+                (1, 0..=3) => ac_tables[id] = table,
+                _ => return Err(Error::ImageReadWrite),
+            }
+            data = &data[17 + total..];
+        }
+        Ok(())
+    }
+
+    fn parse_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() < 6 || data[0] != 8 {
+            return Err(Error::UnsupportedImage);
+        }
+        self.height = u16::from_be_bytes([data[1], data[2]]) as usize;
+        self.width = u16::from_be_bytes([data[3], data[4]]) as usize;
+
+        let components = data
+            .get(6..6 + data[5] as usize * 3)
+            .ok_or(Error::ImageReadWrite)?;
+        for component in components.chunks_exact(3) {
+            let (h, v) = ((component[1] >> 4) as usize, (component[1] & 0x0f) as usize);
+            if h == 0 || v == 0 {
+                return Err(Error::ImageReadWrite);
+            }
+            self.components.push(Component {
+                id: component[0],
+                h,
+                v,
+                blocks_w: 0,
+                blocks: Vec::new(),
+            });
+        }
+
+        self.h_max = self.components.iter().map(|c| c.h).max().unwrap_or(1);
+        self.v_max = self.components.iter().map(|c| c.v).max().unwrap_or(1);
+
+        // Blocks of every component cover whole MCUs
+        let (mcus_w, mcus_h) = self.mcus();
+        for component in &mut self.components {
+            component.blocks_w = mcus_w * component.h;
+            component.blocks = vec![[0; BLOCK_SIZE]; component.blocks_w * mcus_h * component.v];
+        }
+        Ok(())
+    }
+
+    fn mcus(&self) -> (usize, usize) {
+        (
+            self.width.div_ceil(8 * self.h_max),
+            self.height.div_ceil(8 * self.v_max),
+        )
+    }
+
+    fn parse_scan(
+        &self,
+        data: &[u8],
+        dc_tables: &[Option<Huffman>; 4],
+        ac_tables: &[Option<Huffman>; 4],
+        restart_interval: usize,
+    ) -> Result<Scan, Error> {
+        let count = *data.first().ok_or(Error::ImageReadWrite)? as usize;
+        let selectors = data.get(1..1 + count * 2).ok_or(Error::ImageReadWrite)?;
+
+        // Baseline scans always hold the whole spectrum at full precision
+        if data.get(1 + count * 2..4 + count * 2) != Some(&[0, 63, 0][..]) {
+            return Err(Error::UnsupportedImage);
+        }
+
+        let components = selectors
+            .chunks_exact(2)
+            .map(|selector| {
+                let index = self
+                    .components
+                    .iter()
+                    .position(|c| c.id == selector[0])
+                    .ok_or(Error::ImageReadWrite)?;
+                let table = |tables: &[Option<Huffman>; 4], id: u8| {
+                    tables
+                        .get(id as usize)
+                        .cloned()
+                        .flatten()
+                        .ok_or(Error::ImageReadWrite)
+                };
+                Ok(ScanComponent {
+                    index,
+                    dc: table(dc_tables, selector[1] >> 4)?,
+                    ac: table(ac_tables, selector[1] & 0x0f)?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Scan {
+            components,
+            restart_interval,
+        })
+    }
+
+    /// Blocks of each MCU of the scan, as indices of the scan component and of its block
+    fn scan_mcus(&self, scan: &Scan) -> Vec<Vec<(usize, usize)>> {
+        if let [single] = &scan.components[..] {
+            // Non interleaved scans only code the blocks covering the image, one per MCU
+            let component = &self.components[single.index];
+            let blocks_w = (self.width * component.h).div_ceil(self.h_max).div_ceil(8);
+            let blocks_h = (self.height * component.v).div_ceil(self.v_max).div_ceil(8);
+            return (0..blocks_h)
+                .flat_map(|y| (0..blocks_w).map(move |x| vec![(0, y * component.blocks_w + x)]))
+                .collect();
+        }
+
+        let (mcus_w, mcus_h) = self.mcus();
+        (0..mcus_h)
+            .flat_map(|my| (0..mcus_w).map(move |mx| (mx, my)))
+            .map(|(mx, my)| {
+                let mut blocks = Vec::new();
+                for (i, scan_component) in scan.components.iter().enumerate() {
+                    let component = &self.components[scan_component.index];
+                    for v in 0..component.v {
+                        for h in 0..component.h {
+                            let (x, y) = (mx * component.h + h, my * component.v + v);
+                            blocks.push((i, y * component.blocks_w + x));
+                        }
+                    }
+                }
+                blocks
+            })
+            .collect()
+    }
+
+    /// Decodes the entropy coded data of the scan, returns the number of bytes it takes
+    fn decode_scan(&mut self, scan: &Scan, bytes: &[u8]) -> Result<usize, Error> {
+        // Split the data in restart intervals, removing the byte stuffing
+        let mut intervals = vec![Vec::new()];
+        let mut position = 0;
+        while position < bytes.len() {
+            match (bytes[position], bytes.get(position + 1)) {
+                (0xff, Some(0)) => {
+                    intervals.last_mut().unwrap().push(0xff);
+                    position += 2;
+                }
+                (0xff, Some(RST0..=RST7)) => {
+                    intervals.push(Vec::new());
+                    position += 2;
+                }
+                (0xff, _) => break,
+                (byte, _) => {
+                    intervals.last_mut().unwrap().push(byte);
+                    position += 1;
+                }
+            }
+        }
+
+        let mcus = self.scan_mcus(scan);
+        let per_interval = match scan.restart_interval {
+            0 => mcus.len().max(1),
+            interval => interval,
+        };
+
+        for (mcus, interval) in mcus.chunks(per_interval).zip(intervals.iter()) {
+            let mut reader = BitReader {
+                bytes: interval,
+                position: 0,
+            };
+            let mut predictions = vec![0i16; scan.components.len()];
+
+            for &(i, block) in mcus.iter().flatten() {
+                let scan_component = &scan.components[i];
+                let mut coefficients = [0; BLOCK_SIZE];
+
+                let size = scan_component.dc.decode(&mut reader)?;
+                predictions[i] = predictions[i]
+                    .checked_add(extend(reader.bits(size)?, size))
+                    .ok_or(Error::ImageReadWrite)?;
+                coefficients[0] = predictions[i];
+
+                let mut k = 1;
+                while k < BLOCK_SIZE {
+                    let symbol = scan_component.ac.decode(&mut reader)?;
+                    let (run, size) = ((symbol >> 4) as usize, symbol & 0x0f);
+                    if size == 0 {
+                        if run != 15 {
+                            break;
+                        }
+                        k += 16;
+                        continue;
+                    }
+                    k += run;
+                    *coefficients.get_mut(k).ok_or(Error::ImageReadWrite)? =
+                        extend(reader.bits(size)?, size);
+                    k += 1;
+                }
+
+                self.components[scan_component.index].blocks[block] = coefficients;
+            }
+        }
+
+        Ok(position)
+    }
+
+    fn encode_scan(&self, scan: &Scan, output: &mut Vec<u8>) -> Result<(), Error> {
+        let mcus = self.scan_mcus(scan);
+        let per_interval = match scan.restart_interval {
+            0 => mcus.len().max(1),
+            interval => interval,
+        };
+
+        for (n, mcus) in mcus.chunks(per_interval).enumerate() {
+            if n > 0 {
+                output.extend_from_slice(&[0xff, RST0 + ((n - 1) % 8) as u8]);
+            }
+
+            let mut writer = BitWriter::default();
+            let mut predictions = vec![0; scan.components.len()];
+
+            for &(i, block) in mcus.iter().flatten() {
+                let scan_component = &scan.components[i];
+                let coefficients = &self.components[scan_component.index].blocks[block];
+
+                let difference = coefficients[0] - predictions[i];
+                predictions[i] = coefficients[0];
+                let size = category(difference);
+                scan_component.dc.encode(&mut writer, size)?;
+                writer.bits(magnitude_bits(difference, size), size);
+
+                let mut run = 0;
+                for &coefficient in &coefficients[1..] {
+                    if coefficient == 0 {
+                        run += 1;
+                        continue;
+                    }
+                    while run > 15 {
+                        scan_component.ac.encode(&mut writer, 0xf0)?;
+                        run -= 16;
+                    }
+                    let size = category(coefficient);
+                    scan_component.ac.encode(&mut writer, (run << 4) | size)?;
+                    writer.bits(magnitude_bits(coefficient, size), size);
+                    run = 0;
+                }
+                if run > 0 {
+                    scan_component.ac.encode(&mut writer, 0x00)?;
+                }
+            }
+
+            writer.flush();
+            output.append(&mut writer.bytes);
+        }
+        Ok(())
+    }
+
+    /// AC coefficients able to hold the payload, in component, block and zigzag order
+    fn coefficients(&mut self) -> impl Iterator<Item = &mut i16> {
+        self.components
+            .iter_mut()
+            .flat_map(|component| component.blocks.iter_mut())
+            .flat_map(|block| block[1..].iter_mut())
+            .filter(|coefficient| coefficient.unsigned_abs() >= MIN_MAGNITUDE)
+    }
+
+    /// Magnitudes of the coefficients able to hold the payload
+    pub fn samples(&self) -> Vec<u16> {
+        self.components
+            .iter()
+            .flat_map(|component| component.blocks.iter())
+            .flat_map(|block| block[1..].iter())
+            .map(|coefficient| coefficient.unsigned_abs())
+            .filter(|&magnitude| magnitude >= MIN_MAGNITUDE)
+            .collect()
+    }
+
+    /// Sets the magnitudes of the coefficients able to hold the payload, keeping their sign.
+    /// The magnitudes must be at least 2 and in the same size category, as LSB changes keep them
+    pub fn set_samples(&mut self, samples: &[u16]) {
+        for (coefficient, &sample) in self.coefficients().zip(samples) {
+            *coefficient = coefficient.signum() * sample as i16;
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut output = Vec::new();
+        for part in &self.parts {
+            match part {
+                Part::Raw(bytes) => output.extend_from_slice(bytes),
+                Part::Scan(scan) => self.encode_scan(scan, &mut output)?,
+            }
+        }
+        fs::write(path, output)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{category, extend, magnitude_bits, Jpeg, Part, DRI};
+    use crate::utils::Tmp;
+    use std::path::Path;
+
+    #[test]
+    fn test_magnitude() {
+        for &value in &[-1023, -64, -3, -2, -1, 1, 2, 3, 64, 1023] {
+            let size = category(value);
+            assert_eq!(extend(magnitude_bits(value, size), size), value);
+        }
+        assert_eq!(category(0), 0);
+        assert_eq!(category(-3), 2);
+        assert_eq!(category(4), 3);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut jpeg = Jpeg::open(Path::new("./samples/the-matrix.jpg"))
+            .unwrap()
+            .unwrap();
+
+        // Encoding the unchanged coefficients gives back the original file
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("jpg");
+        jpeg.save(path).unwrap();
+        assert_eq!(
+            std::fs::read(path).unwrap(),
+            std::fs::read("./samples/the-matrix.jpg").unwrap()
+        );
+
+        // LSB changes are kept and decode to a similar image
+        let samples: Vec<u16> = jpeg.samples().iter().map(|s| s ^ 1).collect();
+        jpeg.set_samples(&samples);
+        jpeg.save(path).unwrap();
+
+        let changed = Jpeg::open(path).unwrap().unwrap();
+        assert_eq!(changed.samples(), samples);
+        image::open(path).unwrap();
+
+        assert!(Jpeg::open(Path::new("./samples/the-matrix-reloaded.png"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_malformed() {
+        // Segment lengths count their own two bytes
+        for length in 0..2 {
+            assert!(Jpeg::parse(&[0xff, 0xd8, 0xff, 0xe0, 0, length, 0, 0]).is_err());
+        }
+        assert!(Jpeg::parse(&[0xff, 0xd8, 0xff, 0xe0, 0, 8, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_restart_intervals() {
+        let mut jpeg = Jpeg::open(Path::new("./samples/the-matrix.jpg"))
+            .unwrap()
+            .unwrap();
+
+        // A restart marker after every MCU, far more than the 8 markers cycled through
+        if let Some(Part::Raw(bytes)) = jpeg.parts.first_mut() {
+            bytes.splice(2..2, [0xff, DRI, 0, 4, 0, 1]);
+        }
+        for part in &mut jpeg.parts {
+            if let Part::Scan(scan) = part {
+                scan.restart_interval = 1;
+            }
+        }
+
+        let samples: Vec<u16> = jpeg.samples().iter().map(|s| s ^ 1).collect();
+        jpeg.set_samples(&samples);
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("jpg");
+        jpeg.save(path).unwrap();
+
+        let changed = Jpeg::open(path).unwrap().unwrap();
+        assert_eq!(changed.samples(), samples);
+        image::open(path).unwrap();
+    }
+}
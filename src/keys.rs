@@ -0,0 +1,198 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::crypto::KEY_SIZE;
+use crate::errors::Error;
+
+/// Prefixes identifying the kind of key stored in a key file, followed by the hex encoded key
+const IDENTITY_PREFIX: &str = "PISS-X25519-SECRET-KEY-";
+const RECIPIENT_PREFIX: &str = "piss-x25519-public-key-";
+const SIGNING_PREFIX: &str = "PISS-ED25519-SECRET-KEY-";
+const VERIFYING_PREFIX: &str = "piss-ed25519-public-key-";
+
+/// Generates a new X25519 key pair. The identity is written to the given path and the
+/// recipient public key next to it with a `.pub` extension, whose path is returned
+pub fn generate_identity(path: &Path) -> Result<PathBuf, Error> {
+    let identity = StaticSecret::random_from_rng(OsRng);
+    let recipient = PublicKey::from(&identity);
+
+    write_pair(
+        path,
+        (IDENTITY_PREFIX, identity.as_bytes()),
+        (RECIPIENT_PREFIX, recipient.as_bytes()),
+    )
+}
+
+/// Generates a new Ed25519 key pair, written the same way as `generate_identity` does
+pub fn generate_signing_key(path: &Path) -> Result<PathBuf, Error> {
+    let signing_key = SigningKey::generate(&mut OsRng);
+    let verifying_key = signing_key.verifying_key();
+
+    write_pair(
+        path,
+        (SIGNING_PREFIX, signing_key.as_bytes()),
+        (VERIFYING_PREFIX, verifying_key.as_bytes()),
+    )
+}
+
+pub fn read_identity(path: &Path) -> Result<StaticSecret, Error> {
+    read_key(path, IDENTITY_PREFIX).map(StaticSecret::from)
+}
+
+pub fn read_recipient(path: &Path) -> Result<PublicKey, Error> {
+    read_key(path, RECIPIENT_PREFIX).map(PublicKey::from)
+}
+
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, Error> {
+    read_key(path, SIGNING_PREFIX).map(|key| SigningKey::from_bytes(&key))
+}
+
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey, Error> {
+    let key = read_key(path, VERIFYING_PREFIX)?;
+    VerifyingKey::from_bytes(&key).map_err(|_| Error::InvalidKey)
+}
+
+fn public_path(path: &Path) -> PathBuf {
+    let mut public_path = path.as_os_str().to_owned();
+    public_path.push(".pub");
+    PathBuf::from(public_path)
+}
+
+/// Writes the secret key to the given path and the public key next to it, returning its path.
+/// Both files are created before either is written, so a failure leaves no lone half of a pair behind
+fn write_pair(path: &Path, secret: (&str, &[u8]), public: (&str, &[u8])) -> Result<PathBuf, Error> {
+    let public_path = public_path(path);
+    let mut secret_file = create_key(path, true)?;
+    let mut public_file = match create_key(&public_path, false) {
+        Ok(file) => file,
+        Err(error) => {
+            let _ = std::fs::remove_file(path);
+            return Err(error);
+        }
+    };
+
+    let written = write_key(&mut secret_file, secret.0, secret.1)
+        .and_then(|_| write_key(&mut public_file, public.0, public.1));
+    if written.is_err() {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(&public_path);
+    }
+    written.map(|_| public_path)
+}
+
+/// Creates the key file, never overwriting an existing one. Secret keys are only readable by the owner
+fn create_key(path: &Path, secret: bool) -> Result<File, Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if secret { 0o600 } else { 0o644 });
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+
+    Ok(options.open(path)?)
+}
+
+fn write_key(file: &mut File, prefix: &str, key: &[u8]) -> Result<(), Error> {
+    writeln!(file, "{}{}", prefix, to_hex(key))?;
+    Ok(())
+}
+
+fn read_key(path: &Path, prefix: &str) -> Result<[u8; KEY_SIZE], Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let hex = contents
+        .trim()
+        .strip_prefix(prefix)
+        .ok_or(Error::InvalidKey)?;
+
+    let bytes = from_hex(hex).ok_or(Error::InvalidKey)?;
+    if bytes.len() != KEY_SIZE {
+        return Err(Error::InvalidKey);
+    }
+
+    let mut key = [0; KEY_SIZE];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        from_hex, generate_identity, generate_signing_key, read_identity, read_recipient,
+        read_signing_key, read_verifying_key, to_hex,
+    };
+    use crate::utils::Tmp;
+    use x25519_dalek::PublicKey;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("000ff"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_generate_identity() {
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("key");
+        tmp.add(&path.with_extension("key.pub"));
+        let public_path = generate_identity(path).unwrap();
+        assert_eq!(public_path, path.with_extension("key.pub"));
+
+        let identity = read_identity(path).unwrap();
+        let recipient = read_recipient(&public_path).unwrap();
+        assert_eq!(PublicKey::from(&identity), recipient);
+
+        // Keys are never overwritten and each file holds a single kind of key
+        assert!(generate_identity(path).is_err());
+        assert!(read_identity(&public_path).is_err());
+        assert!(read_recipient(path).is_err());
+        assert!(read_signing_key(path).is_err());
+
+        // An existing public key is kept, and no identity is left without it
+        let path = &tmp.path("key");
+        let public_path = path.with_extension("key.pub");
+        tmp.add(&public_path);
+        std::fs::write(&public_path, "existing").unwrap();
+        assert!(generate_identity(path).is_err());
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_to_string(&public_path).unwrap(), "existing");
+    }
+
+    #[test]
+    fn test_generate_signing_key() {
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("key");
+        tmp.add(&path.with_extension("key.pub"));
+        let public_path = generate_signing_key(path).unwrap();
+
+        let signing_key = read_signing_key(path).unwrap();
+        let verifying_key = read_verifying_key(&public_path).unwrap();
+        assert_eq!(signing_key.verifying_key(), verifying_key);
+        assert!(read_recipient(&public_path).is_err());
+    }
+}
@@ -26,31 +26,232 @@
 //! piss decode samples/the-matrix-reloaded.png samples/secret-reloaded.txt
 //! ```
 //!
+//! Or restore it with its original file name, permissions and modification time:
+//! ```sh
+//! piss decode samples/the-matrix-reloaded.png --output-dir samples/
+//! ```
+//!
+//! ### Password protection
+//! The secret can be encrypted (Argon2id + ChaCha20-Poly1305) so only those knowing the password can read it:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --password "red pill"
+//! piss decode samples/the-matrix-reloaded.png samples/secret-reloaded.txt --password "red pill"
+//! ```
+//!
+//! ### Public key encryption
+//! To share secrets without sharing a password, every recipient generates a key pair once:
+//! ```sh
+//! piss keygen neo.key
+//! ```
+//!
+//! The secret can then be encrypted to one or more public keys, and decoded by any of the recipients with their secret key:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png -r neo.key.pub -r trinity.key.pub
+//! piss decode samples/the-matrix-reloaded.png samples/secret-reloaded.txt --identity neo.key
+//! ```
+//!
+//! ### Signatures
+//! To prove who embedded a secret, generate a signing key pair and sign the payload when encoding:
+//! ```sh
+//! piss keygen --signing morpheus.key
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --sign morpheus.key
+//! ```
+//!
+//! Anyone with the public key can then check the signature, without recovering the secret:
+//! ```sh
+//! piss verify samples/the-matrix-reloaded.png --pubkey morpheus.key.pub
+//! ```
+//!
+//! ### Compression
+//! Large text secrets such as logs may only fit in the image once compressed:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --compress
+//! ```
+//!
+//! The secret is stored uncompressed if compression does not reduce its size, decoding needs no extra option.
+//!
+//! ### Scattering
+//! By default the secret is written to consecutive image bytes. A passphrase scatters it over the whole image
+//! in a pseudo-random order instead, which is needed again to decode it. The preamble holding the embedding
+//! parameters is scattered too, so it is not found at a fixed position either:
+//! ```sh
+//! piss --scatter "white rabbit" encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png
+//! piss --scatter "white rabbit" decode samples/the-matrix-reloaded.png samples/secret-reloaded.txt
+//! ```
+//!
+//! ### Unused image bytes
+//! Image bytes not holding the secret are left untouched, so the image keeps its natural LSB statistics.
+//! Alternatively, they can be filled with random noise so the secret length can not be guessed:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --noise
+//! ```
+//! When scattering, the noise is keyed by the passphrase too, so only those knowing it can tell it apart from the secret.
+//!
+//! ### LSB matching
+//! Replacing the LSBs leaves pairs of values artefacts in the image histogram, which statistical attacks detect.
+//! LSB matching randomly moves the image bytes up or down to the nearest value holding the secret bits instead:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --matching
+//! ```
+//! Decoding is the same for both modes, so it needs no extra option.
+//!
+//! ### Matrix embedding
+//! Small secrets in large images can be embedded with Hamming codes, which hold k bits of the secret in the LSBs of
+//! a block of 2^k - 1 image bytes changing at most one of them. The largest k the secret fits with is picked automatically
+//! and stored in the image, so decoding needs no extra option:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --matrix
+//! ```
+//! Matrix embedding always uses a single bit per image byte, and can be combined with LSB matching.
+//!
+//! ### Adaptive embedding
+//! Changes in smooth areas of the image are the easiest to detect. Adaptive embedding weighs every image byte by the
+//! texture around it, and uses syndrome-trellis codes to hold the secret while changing mostly bytes in noisy areas:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --adaptive
+//! ```
+//! Like matrix embedding it uses a single bit per image byte, and the decoder needs neither the costs nor any extra option.
+//!
+//! ### Textured areas
+//! Changes in smooth gradients such as a clear sky stand out. The secret can be restricted to image bytes whose
+//! 3x3 neighbourhood variance, on a 0-255 scale, exceeds a threshold:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --texture 200
+//! ```
+//! The variance ignores the bits holding the secret, so the decoder finds the same image bytes from the threshold
+//! stored in the image. The capacity is reduced accordingly, and LSB matching and palette or JPEG images are not supported.
+//!
+//! ### Pixel-value differencing
+//! Pixel-value differencing holds the secret in the difference between pairs of neighbouring image bytes of the same
+//! channel, so pairs in edges hold more bits than those in smooth areas. The differences are split in ranges of widths
+//! given as powers of two adding up to 256, by default `8,8,16,32,64,128`, and each pair holds as many bits as the
+//! logarithm of the width of its range:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --pvd
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --pvd 4,4,8,16,32,64,128
+//! ```
+//! The range table is stored in the image, so decoding needs no extra option. Only 8-bit truecolour and grayscale images
+//! are supported, without LSB matching or a texture threshold.
+//!
+//! ### Colour channels
+//! The secret can be restricted to some of the colour channels, e.g. blue only, to reduce visible artefacts.
+//! The image capacity is reduced accordingly, and the same channels are needed to decode it:
+//! ```sh
+//! piss --channels b encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png
+//! piss --channels b decode samples/the-matrix-reloaded.png samples/secret-reloaded.txt
+//! ```
+//!
+//! Grayscale images are kept grayscale, their single channel holds the secret whenever any colour channel is selected.
+//! Transparent images keep their alpha channel, which can hold the secret too when selected with `a`:
+//! ```sh
+//! piss --channels rgba encode transparent.png samples/secret.txt transparent-reloaded.png
+//! ```
+//!
+//! Each channel can also use its own number of bits, e.g. more in blue where changes are least visible.
+//! The bits of every channel are stored in the image, so decoding needs no extra option:
+//! ```sh
+//! piss -b r2,g1,b3 encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png
+//! ```
+//! Channels left out of the spec hold no bits of the secret, and alpha still needs selecting with `--channels`.
+//! Matrix, adaptive and PVD embedding do not support it.
+//!
+//! ### Palette images
+//! Indexed PNGs and GIFs stay indexed instead of being converted to truecolour.
+//! Their palette is sorted by transparency, then chained from each colour to its nearest one in CIELAB, and the secret
+//! is held by the LSBs of the pixel indices, which only ever move pixels to a similar colour of the palette.
+//! Pixels whose indices would reach an entry of another transparency are left untouched, and LSB matching is not
+//! supported, as it could carry changes over to those entries:
+//! ```sh
+//! piss -b 1 encode indexed.gif samples/secret.txt indexed-reloaded.gif
+//! ```
+//! The output must be saved as PNG or GIF, animated GIFs are not supported.
+//!
+//! ### JPEG images
+//! JPEG images can be saved as `.jpg` when the input is a JPEG too. The secret is then held by the LSBs of the
+//! quantised DCT coefficients of magnitude 2 or more, the image is never recompressed and the decoder finds it there:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.jpg
+//! piss decode samples/the-matrix-reloaded.jpg samples/secret-reloaded.txt
+//! ```
+//! Only baseline JPEGs are supported, using a single bit per coefficient, the default for JPEG outputs,
+//! and without LSB matching.
+//!
+//! ### Error correction
+//! A few flipped bits, e.g. from an image optimiser or a touch-up in a pixel editor, are enough to lose the secret.
+//! Reed-Solomon codes protect it against them, with the number of parity bytes per block of 255 bytes as redundancy,
+//! 32 by default:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --ecc
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --ecc 64
+//! ```
+//! Every block survives up to half as many corrupted bytes as it has parity bytes, at the cost of as much capacity.
+//! The redundancy is stored in the image, and decoding reports how many corrupted bytes it corrected.
+//!
+//! ### Verification
+//! The saved image can be decoded again right after encoding, failing and removing it unless it holds exactly the
+//! embedded payload:
+//! ```sh
+//! piss encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png --verify
+//! ```
+//! Unencrypted secrets are also recovered and compared with the secret file, encrypted ones are checked as embedded,
+//! without needing the password or identity.
+//!
 //! ### Miscelaneous
-//! By default, PISS uses 2 bits per image byte to encode the secret, you can change this value if desired:
+//! By default, PISS uses 2 bits per image sample to encode the secret, you can change this value if desired:
 //! ```sh
 //! piss -b 4 encode samples/the-matrix.jpg samples/secret.txt samples/the-matrix-reloaded.png
 //! ```
 //!
-//! Just remember to decode using the same number of bits, otherwise the output will be garbage:
+//! The number of bits is stored in the image, so there is no need to repeat it when decoding:
+//! ```sh
+//! piss decode samples/the-matrix-reloaded.png samples/secret-reloaded.txt
+//! ```
+//! If given anyway, decoding fails unless it matches the stored one. Every bit of these parameters is stored three times
+//! and read by majority, so a single flipped copy does not prevent decoding.
+//!
+//! 16-bit images such as scientific PNGs or TIFFs keep their depth, and can use up to 16 bits per sample:
 //! ```sh
-//! piss -b 4 decode samples/the-matrix-reloaded.png samples/secret-reloaded.txt
+//! piss -b 12 encode scan-16bit.png samples/secret.txt scan-16bit-reloaded.png
 //! ```
 //!
 //! ## Important note
-//! It is not recommended to encode secrets and save the output as `.jpg` as compression is performed and the secret is lost.
+//! Saving the output in a lossy format loses the secret, `.jpg` outputs are only possible from JPEG inputs as explained above.
+//!
+//! Encoding fails instead when the output format is lossy, i.e. JPEG from other images, WebP or GIF from truecolour images.
+//! It can still be forced with `--allow-lossy`, though the secret is then unlikely to be recovered.
 
+mod channels;
+mod cover;
+mod crypto;
 mod decoder;
+mod ecc;
 mod encoder;
 mod errors;
+mod header;
+mod jpeg;
+mod keys;
+mod matrix;
+mod metadata;
+mod options;
+mod palette;
+mod payload;
+mod preamble;
+mod pvd;
+mod scatter;
+mod stc;
 mod utils;
 
-use std::path::PathBuf;
+use ed25519_dalek::VerifyingKey;
+use image::ImageFormat;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
+use channels::{ChannelBits, Channels};
 use decoder::Decoder;
 use encoder::Encoder;
 use errors::Error;
+use options::Options;
+use pvd::Table;
 use utils::ByteMask;
 
 #[derive(StructOpt)]
@@ -62,12 +263,87 @@ enum Command {
         secret: PathBuf,
         #[structopt(parse(from_os_str))]
         output: PathBuf,
+        /// Encrypts the secret with a key derived from this password
+        #[structopt(short, long)]
+        password: Option<String>,
+        /// Encrypts the secret to the public key in this file, can be repeated for several recipients
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            number_of_values = 1,
+            conflicts_with = "password"
+        )]
+        recipient: Vec<PathBuf>,
+        /// Signs the payload with the Ed25519 secret key in this file
+        #[structopt(short, long, parse(from_os_str))]
+        sign: Option<PathBuf>,
+        /// Compresses the secret before embedding it, skipped if it does not reduce its size
+        #[structopt(short = "z", long)]
+        compress: bool,
+        /// Fills the LSBs not holding the secret with random noise instead of leaving them untouched
+        #[structopt(long)]
+        noise: bool,
+        /// Moves image bytes up or down to hold the secret bits instead of replacing their LSBs
+        #[structopt(short, long)]
+        matching: bool,
+        /// Embeds with Hamming codes, changing fewer image bytes when the secret is small. Uses 1 bit per sample
+        #[structopt(long)]
+        matrix: bool,
+        /// Embeds with syndrome-trellis codes, changing image bytes in textured areas first. Uses 1 bit per sample
+        #[structopt(long, conflicts_with = "matrix")]
+        adaptive: bool,
+        /// Embeds with pixel-value differencing, holding more bits in pairs of contrasting image bytes. Takes the
+        /// comma separated widths of the difference ranges, powers of two adding up to 256 [default: 8,8,16,32,64,128]
+        #[structopt(long, conflicts_with_all = &["matrix", "adaptive"])]
+        pvd: Option<Option<Table>>,
+        /// Protects the secret with Reed-Solomon codes, taking the number of parity bytes per block of 255 bytes.
+        /// Up to half as many corrupted bytes per block are corrected when decoding [default: 32]
+        #[structopt(long)]
+        ecc: Option<Option<u8>>,
+        /// Only embeds in image bytes whose neighbourhood variance exceeds this threshold, skipping smooth areas
+        #[structopt(long)]
+        texture: Option<u16>,
+        /// Format of the output image, e.g. png or bmp, overriding the one implied by its extension
+        #[structopt(long, parse(try_from_str = parse_format))]
+        format: Option<ImageFormat>,
+        /// Saves the output even in a lossy format, which destroys the secret
+        #[structopt(long)]
+        allow_lossy: bool,
+        /// Decodes the saved image and fails unless it holds the embedded secret
+        #[structopt(long)]
+        verify: bool,
     },
     Decode {
         #[structopt(parse(from_os_str))]
         image: PathBuf,
+        #[structopt(parse(from_os_str), required_unless = "output-dir")]
+        output: Option<PathBuf>,
+        /// Restores the secret inside this directory using its original file name
+        #[structopt(long, parse(from_os_str), conflicts_with = "output")]
+        output_dir: Option<PathBuf>,
+        /// Password the secret was encrypted with
+        #[structopt(short, long)]
+        password: Option<String>,
+        /// Secret key file of one of the recipients the secret was encrypted to
+        #[structopt(short, long, parse(from_os_str))]
+        identity: Option<PathBuf>,
+    },
+    /// Generates an X25519 key pair, the public key is written next to the secret one with a `.pub` extension
+    Keygen {
         #[structopt(parse(from_os_str))]
         output: PathBuf,
+        /// Generates an Ed25519 signing key pair instead
+        #[structopt(long)]
+        signing: bool,
+    },
+    /// Checks the payload signature without recovering the secret
+    Verify {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        /// Ed25519 public key file of the signer
+        #[structopt(long, parse(from_os_str))]
+        pubkey: PathBuf,
     },
 }
 
@@ -78,8 +354,20 @@ enum Command {
     author = "Aitor Ruano <codearm@pm.me>"
 )]
 struct Opt {
-    #[structopt(short = "b", long = "bits", default_value = "2")]
-    bits: u8,
+    /// Number of bits per image sample used to encode the secret, up to the image depth [default: 2, 1 with --matrix, --adaptive, --pvd or JPEG outputs].
+    /// Can be given per channel instead, e.g. r2,g1,b3. Read from the image when decoding, failing if given and different
+    #[structopt(short = "b", long = "bits", parse(try_from_str = parse_bits))]
+    bits: Option<Bits>,
+
+    /// Scatters the secret over the image in a pseudo-random order derived from this passphrase.
+    /// The same passphrase is needed to decode it
+    #[structopt(long)]
+    scatter: Option<String>,
+
+    /// Channels holding the secret, as a combination of r, g, b and a (alpha).
+    /// The same channels are needed to decode it
+    #[structopt(long, default_value = "rgb")]
+    channels: Channels,
 
     #[structopt(subcommand)]
     cmd: Command,
@@ -88,179 +376,827 @@ struct Opt {
 fn main() -> Result<(), Error> {
     let opt = Opt::from_args();
 
-    let mask = ByteMask::new(opt.bits)?;
-
     match opt.cmd {
         Command::Encode {
             image,
             secret,
             output,
-        } => encode(image, secret, output, mask)?,
-        Command::Decode { image, output } => decode(image, output, mask)?,
+            password,
+            recipient,
+            sign,
+            compress,
+            noise,
+            matching,
+            matrix,
+            adaptive,
+            pvd,
+            ecc,
+            texture,
+            format,
+            allow_lossy,
+            verify,
+        } => {
+            // JPEG outputs hold the secret in the LSB of the DCT coefficients
+            let format = output_format(&output, format)?;
+            let single = matrix || adaptive || pvd.is_some() || format == ImageFormat::Jpeg;
+            let (mask, channel_bits) = opt
+                .bits
+                .unwrap_or(Bits::All(if single { 1 } else { 2 }))
+                .mask()?;
+            let options = Options {
+                password,
+                recipients: recipient
+                    .iter()
+                    .map(|path| keys::read_recipient(path))
+                    .collect::<Result<_, _>>()?,
+                signing_key: sign.map(|path| keys::read_signing_key(&path)).transpose()?,
+                compress,
+                noise,
+                matching,
+                matrix,
+                adaptive,
+                pvd: pvd.map(Option::unwrap_or_default),
+                ecc: ecc.map(|parity| parity.unwrap_or(ecc::DEFAULT_PARITY)),
+                texture,
+                format: Some(format),
+                allow_lossy,
+                verify,
+                channel_bits,
+                scatter: opt.scatter,
+                channels: opt.channels,
+                ..Options::default()
+            };
+            encode(image, secret, output, mask, options)?
+        }
+        Command::Decode {
+            image,
+            output,
+            output_dir,
+            password,
+            identity,
+        } => {
+            let (mask, channel_bits) = decoding_mask(opt.bits)?;
+            let options = Options {
+                password,
+                identity: identity
+                    .map(|path| keys::read_identity(&path))
+                    .transpose()?,
+                channel_bits,
+                scatter: opt.scatter,
+                channels: opt.channels,
+                ..Options::default()
+            };
+            match (output, output_dir) {
+                (Some(output), _) => decode(image, output, mask, options)?,
+                (None, Some(output_dir)) => restore(image, output_dir, mask, options)?,
+                (None, None) => unreachable!("output or output dir is required"),
+            }
+        }
+        Command::Keygen { output, signing } => keygen(output, signing)?,
+        Command::Verify { image, pubkey } => {
+            let (mask, channel_bits) = decoding_mask(opt.bits)?;
+            let options = Options {
+                channel_bits,
+                scatter: opt.scatter,
+                channels: opt.channels,
+                ..Options::default()
+            };
+            verify(image, mask, options, keys::read_verifying_key(&pubkey)?)?
+        }
     }
 
     Ok(())
 }
 
-fn encode(image: PathBuf, secret: PathBuf, output: PathBuf, mask: ByteMask) -> Result<(), Error> {
-    let mut encoder = Encoder::new(image, secret, mask)?;
-    encoder.save(output)?;
+/// Bits per image sample given on the command line, a single number or a per channel spec
+#[derive(Clone, Copy)]
+enum Bits {
+    All(u8),
+    Channels(ChannelBits),
+}
+
+impl Bits {
+    /// Mask of the most bits per sample, along with the bits of each channel if given per channel
+    fn mask(self) -> Result<(ByteMask, Option<ChannelBits>), Error> {
+        match self {
+            Bits::All(bits) => Ok((ByteMask::new(bits)?, None)),
+            Bits::Channels(channels) => Ok((ByteMask::new(channels.max())?, Some(channels))),
+        }
+    }
+}
+
+fn parse_bits(bits: &str) -> Result<Bits, Error> {
+    match bits.parse() {
+        Ok(bits) => Ok(Bits::All(bits)),
+        Err(_) if bits.starts_with(|c: char| c.is_ascii_digit()) => Err(Error::InvalidNumberOfBits),
+        Err(_) => bits.parse().map(Bits::Channels),
+    }
+}
+
+/// Bits given to decoders, which always read them from the image preamble and fail if they differ
+fn decoding_mask(bits: Option<Bits>) -> Result<(Option<ByteMask>, Option<ChannelBits>), Error> {
+    match bits {
+        Some(bits) => {
+            let (mask, channel_bits) = bits.mask()?;
+            Ok((Some(mask), channel_bits))
+        }
+        None => Ok((None, None)),
+    }
+}
+
+fn parse_format(format: &str) -> Result<ImageFormat, Error> {
+    ImageFormat::from_extension(format).ok_or(Error::UnsupportedImage)
+}
+
+/// Format of the output image, the one given or else the one implied by its extension
+fn output_format(output: &Path, format: Option<ImageFormat>) -> Result<ImageFormat, Error> {
+    match format {
+        Some(format) => Ok(format),
+        None => Ok(ImageFormat::from_path(output)?),
+    }
+}
+
+fn encode(
+    image: PathBuf,
+    secret: PathBuf,
+    output: PathBuf,
+    mask: ByteMask,
+    options: Options,
+) -> Result<(), Error> {
+    let format = output_format(&output, options.format)?;
+    // JPEG outputs can only hold the secret in the DCT coefficients of a JPEG image
+    let options = Options {
+        format: Some(format),
+        dct: format == ImageFormat::Jpeg,
+        ..options
+    };
+    let mut encoder = Encoder::new(image, secret.clone(), mask, &options)?;
+    encoder.save(output.clone())?;
+
+    // The payload is compared as embedded, so encrypted secrets are checked without decrypting them.
+    // Unencrypted ones are also recovered and compared with the secret file
+    if options.verify {
+        let encrypted = options.password.is_some() || !options.recipients.is_empty();
+        let decoder = Decoder::new(
+            output.clone(),
+            None,
+            Options {
+                scatter: options.scatter.clone(),
+                channels: options.channels,
+                ..Options::default()
+            },
+        );
+        let verified = decoder.is_ok_and(|decoder| {
+            decoder.holds(encoder.payload())
+                && (encrypted
+                    || decoder.read().is_ok_and(|(_, recovered)| {
+                        std::fs::read(&secret).is_ok_and(|secret| secret == recovered)
+                    }))
+        });
+        // The image not holding the secret is not left behind
+        if !verified {
+            std::fs::remove_file(&output)?;
+            return Err(Error::VerificationFailed);
+        }
+    }
+    let available = if options.dct {
+        String::from("the DCT coefficients")
+    } else {
+        format!("channels {}", options.channels)
+    };
+    println!(
+        "Embedded {} of {} bytes available in {}",
+        encoder.payload_size(),
+        encoder.capacity(),
+        available
+    );
     Ok(())
 }
 
-fn decode(image: PathBuf, output: PathBuf, mask: ByteMask) -> Result<(), Error> {
-    let decoder = Decoder::new(image, mask)?;
+fn decode(
+    image: PathBuf,
+    output: PathBuf,
+    mask: Option<ByteMask>,
+    options: Options,
+) -> Result<(), Error> {
+    let decoder = Decoder::new(image, mask, options)?;
     decoder.save(output)?;
+    report_corrected(&decoder);
+    Ok(())
+}
+
+fn restore(
+    image: PathBuf,
+    output_dir: PathBuf,
+    mask: Option<ByteMask>,
+    options: Options,
+) -> Result<(), Error> {
+    let decoder = Decoder::new(image, mask, options)?;
+    let path = decoder.restore(output_dir)?;
+    report_corrected(&decoder);
+    println!("Secret restored to {}", path.display());
+    Ok(())
+}
+
+fn report_corrected(decoder: &Decoder) {
+    if let Some(corrected) = decoder.corrected() {
+        println!("Corrected {} corrupted bytes", corrected);
+    }
+}
+
+fn keygen(output: PathBuf, signing: bool) -> Result<(), Error> {
+    let public_path = if signing {
+        keys::generate_signing_key(&output)?
+    } else {
+        keys::generate_identity(&output)?
+    };
+    println!("Public key written to {}", public_path.display());
+    Ok(())
+}
+
+fn verify(
+    image: PathBuf,
+    mask: Option<ByteMask>,
+    options: Options,
+    verifying_key: VerifyingKey,
+) -> Result<(), Error> {
+    let decoder = Decoder::new(image, mask, options)?;
+    decoder.verify(&verifying_key)?;
+    println!("Signature is valid");
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{decode, encode, ByteMask};
-    use std::io::BufRead;
-    use std::path::PathBuf;
-
-    fn check_secret() {
-        let secret = std::io::BufReader::new(std::fs::File::open("./samples/output.txt").unwrap());
-        let result = secret.lines().next().unwrap().unwrap();
-        assert_eq!(result, "The Matrix has you.");
-    }
+    use super::{
+        decode, encode, parse_bits, parse_format, Bits, ByteMask, Decoder, Error, ImageFormat,
+        Options,
+    };
+    use crate::palette::Palette;
+    use crate::utils::Tmp;
+    use std::path::{Path, PathBuf};
 
-    fn remove_tmp_files() {
-        std::fs::remove_file("./samples/output.png").unwrap();
-        std::fs::remove_file("./samples/output.txt").unwrap();
+    fn assert_secret(output: &Path) {
+        assert_eq!(
+            std::fs::read(output).unwrap(),
+            std::fs::read("./samples/secret.txt").unwrap()
+        );
     }
 
-    #[test]
-    fn test_integration() {
-        let mask = ByteMask::new(1).unwrap();
-        encode(
-            PathBuf::from("./samples/the-matrix.jpg"),
-            PathBuf::from("./samples/secret.txt"),
-            PathBuf::from("./samples/output.png"),
-            mask,
-        )
-        .unwrap();
-        decode(
-            PathBuf::from("./samples/output.png"),
-            PathBuf::from("./samples/output.txt"),
-            mask,
-        )
-        .unwrap();
-        check_secret();
+    /// Encodes the sample secret into `output` and decodes it back. Besides the password, scatter passphrase
+    /// and channels, the decoder reads everything from the image preamble
+    fn roundtrip_to(cover: &str, output: &Path, options: Options, bits: u8) {
+        let mut tmp = Tmp::default();
+        let secret = tmp.path("txt");
+        let decoding = Options {
+            password: options.password.clone(),
+            scatter: options.scatter.clone(),
+            channels: options.channels,
+            ..Options::default()
+        };
 
-        let mask = ByteMask::new(2).unwrap();
         encode(
-            PathBuf::from("./samples/the-matrix.jpg"),
+            PathBuf::from(cover),
             PathBuf::from("./samples/secret.txt"),
-            PathBuf::from("./samples/output.png"),
-            mask,
-        )
-        .unwrap();
-        decode(
-            PathBuf::from("./samples/output.png"),
-            PathBuf::from("./samples/output.txt"),
-            mask,
+            output.to_path_buf(),
+            ByteMask::new(bits).unwrap(),
+            options,
         )
         .unwrap();
-        check_secret();
+        decode(output.to_path_buf(), secret.clone(), None, decoding).unwrap();
+        assert_secret(&secret);
+    }
+
+    /// Round trip of the sample secret through a PNG copy of the sample image, returning its path
+    fn roundtrip(options: Options, bits: u8) -> (Tmp, PathBuf) {
+        let mut tmp = Tmp::default();
+        let image = tmp.path("png");
+        roundtrip_to("./samples/the-matrix.jpg", &image, options, bits);
+        (tmp, image)
+    }
+
+    #[test]
+    fn test_integration() {
+        for bits in 1..=8 {
+            roundtrip(Options::default(), bits);
+        }
+    }
+
+    #[test]
+    fn test_binary_secret() {
+        let mut tmp = Tmp::default();
+        let (secret_path, image, output) = (tmp.path("bin"), tmp.path("png"), tmp.path("bin"));
+        let secret: Vec<u8> = vec![0, 0, 0, 1, 0, 255, 0, 0];
+        std::fs::write(&secret_path, &secret).unwrap();
 
         let mask = ByteMask::new(3).unwrap();
         encode(
             PathBuf::from("./samples/the-matrix.jpg"),
-            PathBuf::from("./samples/secret.txt"),
-            PathBuf::from("./samples/output.png"),
+            secret_path,
+            image.clone(),
             mask,
+            Options::default(),
         )
         .unwrap();
-        decode(
-            PathBuf::from("./samples/output.png"),
-            PathBuf::from("./samples/output.txt"),
-            mask,
-        )
-        .unwrap();
-        check_secret();
+        decode(image, output.clone(), Some(mask), Options::default()).unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), secret);
+    }
 
-        let mask = ByteMask::new(4).unwrap();
-        encode(
-            PathBuf::from("./samples/the-matrix.jpg"),
-            PathBuf::from("./samples/secret.txt"),
-            PathBuf::from("./samples/output.png"),
-            mask,
-        )
-        .unwrap();
-        decode(
-            PathBuf::from("./samples/output.png"),
-            PathBuf::from("./samples/output.txt"),
-            mask,
-        )
-        .unwrap();
-        check_secret();
+    #[test]
+    fn test_password() {
+        let password = Options {
+            password: Some(String::from("red pill")),
+            ..Options::default()
+        };
+        let (mut tmp, image) = roundtrip(password, 2);
+        assert!(decode(image, tmp.path("txt"), None, Options::default()).is_err());
+    }
 
-        let mask = ByteMask::new(5).unwrap();
-        encode(
-            PathBuf::from("./samples/the-matrix.jpg"),
-            PathBuf::from("./samples/secret.txt"),
-            PathBuf::from("./samples/output.png"),
-            mask,
-        )
-        .unwrap();
-        decode(
-            PathBuf::from("./samples/output.png"),
-            PathBuf::from("./samples/output.txt"),
-            mask,
-        )
-        .unwrap();
-        check_secret();
+    #[test]
+    fn test_scatter() {
+        let scatter = |passphrase: &str| Options {
+            scatter: Some(String::from(passphrase)),
+            ..Options::default()
+        };
+        let (mut tmp, image) = roundtrip(scatter("white rabbit"), 1);
 
-        let mask = ByteMask::new(6).unwrap();
-        encode(
-            PathBuf::from("./samples/the-matrix.jpg"),
-            PathBuf::from("./samples/secret.txt"),
-            PathBuf::from("./samples/output.png"),
-            mask,
-        )
-        .unwrap();
-        decode(
-            PathBuf::from("./samples/output.png"),
-            PathBuf::from("./samples/output.txt"),
-            mask,
-        )
-        .unwrap();
-        check_secret();
+        let mut wrong = |options| decode(image.clone(), tmp.path("txt"), None, options);
+        assert!(wrong(Options::default()).is_err());
+        assert!(wrong(scatter("black cat")).is_err());
+    }
 
-        let mask = ByteMask::new(7).unwrap();
-        encode(
-            PathBuf::from("./samples/the-matrix.jpg"),
-            PathBuf::from("./samples/secret.txt"),
-            PathBuf::from("./samples/output.png"),
-            mask,
-        )
-        .unwrap();
-        decode(
-            PathBuf::from("./samples/output.png"),
-            PathBuf::from("./samples/output.txt"),
-            mask,
-        )
-        .unwrap();
-        check_secret();
+    #[test]
+    fn test_matching() {
+        for &bits in &[1, 3, 8] {
+            let matching = Options {
+                matching: true,
+                noise: true,
+                ..Options::default()
+            };
+            roundtrip(matching, bits);
+        }
+    }
 
-        let mask = ByteMask::new(8).unwrap();
-        encode(
+    #[test]
+    fn test_matrix() {
+        // The code parameter is read from the preamble, only the scatter passphrase is needed
+        let matrix = Options {
+            matrix: true,
+            scatter: Some(String::from("white rabbit")),
+            ..Options::default()
+        };
+        let (mut tmp, image) = roundtrip(matrix, 1);
+
+        // Bits given explicitly are checked against the preamble instead of replacing it
+        let mut explicit = |bits| {
+            decode(
+                image.clone(),
+                tmp.path("txt"),
+                Some(ByteMask::new(bits).unwrap()),
+                Options {
+                    scatter: Some(String::from("white rabbit")),
+                    ..Options::default()
+                },
+            )
+        };
+        explicit(1).unwrap();
+        assert!(matches!(explicit(2), Err(Error::BitsMismatch)));
+    }
+
+    #[test]
+    fn test_adaptive() {
+        // The cost map is not needed to decode
+        let adaptive = Options {
+            adaptive: true,
+            channels: "rg".parse().unwrap(),
+            noise: true,
+            ..Options::default()
+        };
+        roundtrip(adaptive, 1);
+    }
+
+    #[test]
+    fn test_texture() {
+        // The threshold is stored in the image, the decoder selects the same samples
+        for &(bits, noise) in &[(3, false), (1, true)] {
+            let texture = Options {
+                texture: Some(200),
+                noise,
+                ..Options::default()
+            };
+            roundtrip(texture, bits);
+        }
+    }
+
+    #[test]
+    fn test_pvd() {
+        // The range table is read from the preamble
+        for &(table, scatter) in &[
+            ("8,8,16,32,64,128", None),
+            ("2,2,4,8,16,32,64,128", Some("white rabbit")),
+        ] {
+            let pvd = Options {
+                pvd: Some(table.parse().unwrap()),
+                scatter: scatter.map(String::from),
+                ..Options::default()
+            };
+            roundtrip(pvd, 1);
+        }
+
+        // Whole samples change, LSB embedding options do not apply
+        let mut tmp = Tmp::default();
+        assert!(matches!(
+            encode(
+                PathBuf::from("./samples/the-matrix.jpg"),
+                PathBuf::from("./samples/secret.txt"),
+                tmp.path("png"),
+                ByteMask::new(1).unwrap(),
+                Options {
+                    pvd: Some(Default::default()),
+                    matching: true,
+                    ..Options::default()
+                },
+            ),
+            Err(Error::UnsupportedMode)
+        ));
+    }
+
+    #[test]
+    fn test_ecc() {
+        let corrupt = |ecc| {
+            let (tmp, image) = roundtrip(
+                Options {
+                    ecc,
+                    ..Options::default()
+                },
+                1,
+            );
+
+            // Flip single LSBs of the payload, past the preamble
+            let mut corrupted = image::open(&image).unwrap().to_rgb8();
+            for &i in &[100, 500, 1000, 2000] {
+                corrupted.as_mut()[i] ^= 1;
+            }
+            corrupted.save(&image).unwrap();
+            (tmp, Decoder::new(image, None, Options::default()).unwrap())
+        };
+
+        let (mut tmp, decoder) = corrupt(Some(16));
+        let output = tmp.path("txt");
+        decoder.save(output.clone()).unwrap();
+        assert_eq!(decoder.corrected(), Some(4));
+        assert_secret(&output);
+
+        // Without error correction the secret is lost
+        let (mut tmp, decoder) = corrupt(None);
+        assert!(decoder.save(tmp.path("txt")).is_err());
+        assert_eq!(decoder.corrected(), None);
+    }
+
+    #[test]
+    fn test_preamble() {
+        let (mut tmp, image) = roundtrip(Options::default(), 2);
+
+        // Every preamble bit is repeated, a single flipped copy is outvoted
+        let mut flipped = image::open(&image).unwrap().to_rgb8();
+        flipped.as_mut()[0] ^= 1;
+        flipped.as_mut()[4] ^= 1;
+        flipped.save(&image).unwrap();
+        let output = tmp.path("txt");
+        decode(image.clone(), output.clone(), None, Options::default()).unwrap();
+        assert_secret(&output);
+
+        // Flipping the other copies of a bit changes it
+        flipped.as_mut()[1] ^= 1;
+        flipped.as_mut()[2] ^= 1;
+        flipped.save(&image).unwrap();
+        assert!(decode(image, tmp.path("txt"), None, Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_channels() {
+        let channels = |channels: &str| Options {
+            channels: channels.parse().unwrap(),
+            ..Options::default()
+        };
+        let (mut tmp, image) = roundtrip(channels("b"), 2);
+
+        // Only the blue channel bytes are changed
+        let original = image::open("./samples/the-matrix.jpg").unwrap().to_rgb8();
+        let encoded = image::open(&image).unwrap().to_rgb8();
+        assert!(original
+            .pixels()
+            .zip(encoded.pixels())
+            .all(|(o, e)| o[0] == e[0] && o[1] == e[1]));
+
+        assert!(decode(image, tmp.path("txt"), None, channels("g")).is_err());
+    }
+
+    #[test]
+    fn test_channel_bits() {
+        // The bits of each channel are read from the preamble
+        let (mask, channel_bits) = parse_bits("r2,g1,b3").unwrap().mask().unwrap();
+        assert_eq!(mask.bits, 3);
+        let (_tmp, image) = roundtrip(
+            Options {
+                channel_bits,
+                ..Options::default()
+            },
+            mask.bits,
+        );
+
+        // Every channel only changes its own number of bits
+        let original = image::open("./samples/the-matrix.jpg").unwrap().to_rgb8();
+        let encoded = image::open(&image).unwrap().to_rgb8();
+        assert!(original.pixels().zip(encoded.pixels()).all(|(o, e)| {
+            (o[0] ^ e[0]) >> 2 == 0 && (o[1] ^ e[1]) >> 1 == 0 && (o[2] ^ e[2]) >> 3 == 0
+        }));
+
+        assert!(matches!(parse_bits("4"), Ok(Bits::All(4))));
+        assert!(matches!(parse_bits("300"), Err(Error::InvalidNumberOfBits)));
+        assert!(matches!(parse_bits("x2"), Err(Error::InvalidChannels)));
+    }
+
+    #[test]
+    fn test_alpha() {
+        let mut tmp = Tmp::default();
+        let (cover, output) = (tmp.path("png"), tmp.path("png"));
+        let image = image::RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([x as u8, y as u8, (x ^ y) as u8, 255 - x as u8])
+        });
+        image.save(&cover).unwrap();
+
+        let alpha = Options {
+            channels: "a".parse().unwrap(),
+            ..Options::default()
+        };
+        roundtrip_to(cover.to_str().unwrap(), &output, alpha, 4);
+
+        // The output keeps the alpha channel and only the alpha bytes are changed
+        let encoded = image::open(&output).unwrap();
+        let encoded = encoded.as_rgba8().unwrap();
+        assert!(image
+            .pixels()
+            .zip(encoded.pixels())
+            .all(|(o, e)| o.0[..3] == e.0[..3]));
+        assert_ne!(&image, encoded);
+    }
+
+    #[test]
+    fn test_16_bit() {
+        let mut tmp = Tmp::default();
+        let (cover, output) = (tmp.path("png"), tmp.path("png"));
+        let image = image::ImageBuffer::from_fn(64, 64, |x, y| {
+            image::Rgb([x as u16 * 1000, y as u16 * 1000, u16::MAX - (x * y) as u16])
+        });
+        image.save(&cover).unwrap();
+        roundtrip_to(cover.to_str().unwrap(), &output, Options::default(), 12);
+
+        // The output keeps the 16-bit depth and only the 12 LSBs are changed
+        let encoded = image::open(&output).unwrap();
+        let encoded = encoded.as_rgb16().unwrap();
+        assert!(image
+            .iter()
+            .zip(encoded.iter())
+            .all(|(o, e)| o >> 12 == e >> 12));
+
+        // 8-bit images can not hold more than 8 bits per sample
+        assert!(encode(
             PathBuf::from("./samples/the-matrix.jpg"),
             PathBuf::from("./samples/secret.txt"),
-            PathBuf::from("./samples/output.png"),
-            mask,
-        )
-        .unwrap();
-        decode(
-            PathBuf::from("./samples/output.png"),
-            PathBuf::from("./samples/output.txt"),
-            mask,
+            output,
+            ByteMask::new(12).unwrap(),
+            Options::default(),
         )
-        .unwrap();
-        check_secret();
+        .is_err());
+    }
+
+    #[test]
+    fn test_grayscale() {
+        let mut tmp = Tmp::default();
+        let (cover, output) = (tmp.path("png"), tmp.path("png"));
+        image::open("./samples/the-matrix.jpg")
+            .unwrap()
+            .to_luma8()
+            .save(&cover)
+            .unwrap();
+        roundtrip_to(cover.to_str().unwrap(), &output, Options::default(), 2);
+
+        // The output stays grayscale instead of being expanded to RGB
+        let encoded = image::open(&output).unwrap();
+        assert!(encoded.as_luma8().is_some());
+    }
+
+    #[test]
+    fn test_palette() {
+        let image = image::open("./samples/the-matrix.jpg").unwrap().to_rgb8();
+        let (width, height) = image.dimensions();
+
+        // Quantize the sample image to a palette of 256 colours, 3 bits of red and green and 2 of blue,
+        // and to 5 grays, whose palette the GIF encoder would pad to 8 entries
+        let quantized = [
+            (
+                (0..=255u8)
+                    .map(|i| {
+                        [
+                            i & 0b1110_0000,
+                            (i << 3) & 0b1110_0000,
+                            (i << 6) & 0b1100_0000,
+                        ]
+                    })
+                    .collect::<Vec<_>>(),
+                image
+                    .pixels()
+                    .map(|p| (p[0] & 0b1110_0000) | ((p[1] & 0b1110_0000) >> 3) | (p[2] >> 6))
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                (0..5u8).map(|i| [i * 63; 3]).collect(),
+                image.pixels().map(|p| p[1] / 52).collect(),
+            ),
+        ];
+
+        for (palette, indices) in &quantized {
+            let mut tmp = Tmp::default();
+            let cover = tmp.path("png");
+            let mut encoder =
+                png::Encoder::new(std::fs::File::create(&cover).unwrap(), width, height);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_palette(palette.concat());
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(indices).unwrap();
+            drop(writer);
+
+            for extension in &["png", "gif"] {
+                let output = tmp.path(extension);
+                roundtrip_to(cover.to_str().unwrap(), &output, Options::default(), 1);
+
+                // The output stays indexed
+                assert!(Palette::open(&output).unwrap().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_palette_alpha() {
+        let mut tmp = Tmp::default();
+        let (cover, output) = (tmp.path("png"), tmp.path("png"));
+        let image = image::open("./samples/the-matrix.jpg").unwrap().to_rgb8();
+        let (width, height) = image.dimensions();
+
+        // Three grays and a transparent entry, used by every fifth pixel
+        let indices: Vec<u8> = image
+            .pixels()
+            .enumerate()
+            .map(|(i, p)| if i % 5 == 0 { 0 } else { 1 + p[1] / 86 })
+            .collect();
+        let mut encoder = png::Encoder::new(std::fs::File::create(&cover).unwrap(), width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(vec![0, 255, 0, 0, 0, 0, 128, 128, 128, 255, 255, 255]);
+        encoder.set_trns(vec![0]);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&indices).unwrap();
+        drop(writer);
+
+        roundtrip_to(cover.to_str().unwrap(), &output, Options::default(), 1);
+
+        // No pixel turns transparent or opaque
+        let alpha = |path: &Path| -> Vec<u8> {
+            let image = image::open(path).unwrap().to_rgba8();
+            image.pixels().map(|p| p[3]).collect()
+        };
+        assert_eq!(alpha(&cover), alpha(&output));
+
+        let matching = Options {
+            matching: true,
+            ..Options::default()
+        };
+        assert!(matches!(
+            encode(
+                cover,
+                PathBuf::from("./samples/secret.txt"),
+                output,
+                ByteMask::new(1).unwrap(),
+                matching,
+            ),
+            Err(Error::UnsupportedMode)
+        ));
+    }
+
+    #[test]
+    fn test_jpeg() {
+        let mut tmp = Tmp::default();
+        let output = tmp.path("jpg");
+        roundtrip_to("./samples/the-matrix.jpg", &output, Options::default(), 1);
+
+        // The output is still a valid JPEG image of the same size
+        let encoded = image::open(&output).unwrap();
+        assert_eq!(encoded.to_rgb8().len(), 417600);
+
+        // Only the LSB of the coefficients of a JPEG image can be used
+        let encode_jpeg = |image: &str, bits, options| {
+            encode(
+                PathBuf::from(image),
+                PathBuf::from("./samples/secret.txt"),
+                output.clone(),
+                ByteMask::new(bits).unwrap(),
+                options,
+            )
+        };
+        assert!(matches!(
+            encode_jpeg("./samples/the-matrix.jpg", 2, Options::default()),
+            Err(Error::DctBits)
+        ));
+        assert!(matches!(
+            encode_jpeg("./samples/the-matrix-reloaded.png", 1, Options::default()),
+            Err(Error::LossyFormat)
+        ));
+        let matching = Options {
+            matching: true,
+            ..Options::default()
+        };
+        assert!(encode_jpeg("./samples/the-matrix.jpg", 1, matching).is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let mut tmp = Tmp::default();
+        let (img, gif) = (tmp.path("img"), tmp.path("gif"));
+
+        // The format overrides the extension, the output is still decoded
+        let bmp = Options {
+            format: Some(parse_format("bmp").unwrap()),
+            ..Options::default()
+        };
+        roundtrip_to("./samples/the-matrix.jpg", &img, bmp, 2);
+        assert_eq!(
+            image::io::Reader::open(&img)
+                .unwrap()
+                .with_guessed_format()
+                .unwrap()
+                .format(),
+            Some(ImageFormat::Bmp)
+        );
+
+        // Lossy formats are refused unless explicitly allowed
+        let encode_gif = |options| {
+            encode(
+                PathBuf::from("./samples/the-matrix.jpg"),
+                PathBuf::from("./samples/secret.txt"),
+                gif.clone(),
+                ByteMask::new(2).unwrap(),
+                options,
+            )
+        };
+        assert!(matches!(
+            encode_gif(Options::default()),
+            Err(Error::LossyFormat)
+        ));
+        assert!(!gif.exists());
+        let allow_lossy = Options {
+            allow_lossy: true,
+            ..Options::default()
+        };
+        encode_gif(allow_lossy).unwrap();
+
+        assert!(parse_format("doc").is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut tmp = Tmp::default();
+        let (png, gif) = (tmp.path("png"), tmp.path("gif"));
+        let encode_verify = |output: &Path, options| {
+            encode(
+                PathBuf::from("./samples/the-matrix.jpg"),
+                PathBuf::from("./samples/secret.txt"),
+                output.to_path_buf(),
+                ByteMask::new(2).unwrap(),
+                Options {
+                    verify: true,
+                    ..options
+                },
+            )
+        };
+
+        let scatter = Options {
+            password: Some(String::from("red pill")),
+            scatter: Some(String::from("white rabbit")),
+            channels: "gb".parse().unwrap(),
+            ..Options::default()
+        };
+        encode_verify(&png, scatter).unwrap();
+        encode_verify(&png, Options::default()).unwrap();
 
-        remove_tmp_files();
+        // Truecolour GIFs are quantised, so the secret does not survive
+        let allow_lossy = Options {
+            allow_lossy: true,
+            ..Options::default()
+        };
+        assert!(matches!(
+            encode_verify(&gif, allow_lossy),
+            Err(Error::VerificationFailed)
+        ));
+        assert!(!gif.exists());
     }
 }
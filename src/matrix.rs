@@ -0,0 +1,70 @@
+/// Largest code parameter, using blocks of 65535 samples
+pub const MAX_K: u8 = 16;
+
+/// Number of samples of a block holding k payload bits
+pub fn block_size(k: u8) -> usize {
+    (1 << k) - 1
+}
+
+/// Picks the largest code parameter whose blocks still fit the payload bits in the given number of samples,
+/// the larger it is the fewer samples change per payload bit. Returns `None` if not even k = 1 fits
+pub fn parameter(bits: usize, samples: usize) -> Option<u8> {
+    (1..=MAX_K)
+        .rev()
+        .find(|&k| bits.div_ceil(k as usize) * block_size(k) <= samples)
+}
+
+/// Hamming syndrome of a block of samples, the XOR of the 1-based positions of the samples with their LSB set.
+/// A block of 2^k - 1 samples holds k payload bits in it, reached by changing the LSB of at most one sample
+pub fn syndrome<I: IntoIterator<Item = u16>>(samples: I) -> u16 {
+    samples
+        .into_iter()
+        .enumerate()
+        .filter(|(_, sample)| sample & 1 == 1)
+        .fold(0, |syndrome, (i, _)| syndrome ^ (i + 1) as u16)
+}
+
+/// Position in the block of the sample whose LSB has to change for the syndrome to become the message bits,
+/// `None` if the block already holds them
+pub fn change<I: IntoIterator<Item = u16>>(samples: I, message: u16) -> Option<usize> {
+    match syndrome(samples) ^ message {
+        0 => None,
+        position => Some(position as usize - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_size, change, parameter, syndrome};
+
+    #[test]
+    fn test_parameter() {
+        assert_eq!(block_size(1), 1);
+        assert_eq!(block_size(3), 7);
+
+        // Filling every sample is plain LSB embedding
+        assert_eq!(parameter(100, 100), Some(1));
+        assert_eq!(parameter(100, 99), None);
+        assert_eq!(parameter(9, 21), Some(3));
+        assert_eq!(parameter(8, 1000), Some(9));
+        assert_eq!(parameter(0, 0), Some(16));
+    }
+
+    #[test]
+    fn test_change() {
+        let mut block = [10u16, 3, 4, 7, 8, 255, 0];
+        assert_eq!(syndrome(block.iter().copied()), 2 ^ 4 ^ 6);
+
+        // A single change reaches every message
+        for message in 0..8 {
+            let mut changed = block;
+            if let Some(i) = change(block.iter().copied(), message) {
+                changed[i] ^= 1;
+            }
+            assert_eq!(syndrome(changed.iter().copied()), message);
+        }
+
+        block[3] ^= 1;
+        assert_eq!(change(block.iter().copied(), 2 ^ 6), None);
+    }
+}
@@ -0,0 +1,194 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::Error;
+
+/// Original file information of the secret, stored in the payload in front of the secret
+/// so it can be restored with the same name, permissions and modification time
+#[derive(Debug, PartialEq)]
+pub struct Metadata {
+    pub name: String,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+}
+
+impl Metadata {
+    pub fn new(path: &Path, file: &File) -> Result<Self, Error> {
+        let metadata = file.metadata()?;
+
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Ok(Metadata {
+            name,
+            size: metadata.len(),
+            mode: Self::mode(&metadata.permissions()),
+            mtime,
+        })
+    }
+
+    #[cfg(unix)]
+    fn mode(permissions: &std::fs::Permissions) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.mode()
+    }
+
+    #[cfg(not(unix))]
+    fn mode(permissions: &std::fs::Permissions) -> u32 {
+        if permissions.readonly() {
+            0o444
+        } else {
+            0o644
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // File names longer than the u16 range are not allowed by any filesystem anyway
+        let name = &self.name.as_bytes()[..self.name.len().min(u16::MAX as usize)];
+
+        let mut bytes = Vec::with_capacity(22 + name.len());
+        bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(name);
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&self.mode.to_be_bytes());
+        bytes.extend_from_slice(&self.mtime.to_be_bytes());
+        bytes
+    }
+
+    /// Parses the metadata in front of the secret, returns it together with the secret bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        if bytes.len() < 2 {
+            return Err(Error::CorruptedSecret);
+        }
+
+        let length = u16::from_be_bytes(bytes[..2].try_into().unwrap()) as usize;
+        if bytes.len() < 22 + length {
+            return Err(Error::CorruptedSecret);
+        }
+
+        let (name, bytes) = bytes[2..].split_at(length);
+        let metadata = Metadata {
+            name: String::from_utf8_lossy(name).into_owned(),
+            size: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            mode: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            mtime: u64::from_be_bytes(bytes[12..20].try_into().unwrap()),
+        };
+
+        let secret = &bytes[20..];
+        if secret.len() as u64 != metadata.size {
+            return Err(Error::CorruptedSecret);
+        }
+
+        Ok((metadata, secret))
+    }
+
+    /// Returns the path to restore the secret to inside the given directory.
+    /// Only the last component of the stored name is kept, so it can never point outside of it
+    pub fn path_in(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let name: String = self
+            .name
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or_default()
+            .chars()
+            // Drive separators would make the path absolute on Windows
+            .filter(|c| !c.is_control() && *c != ':')
+            .collect();
+
+        if name.is_empty() || name == "." || name == ".." {
+            Err(Error::UnsafeFileName)
+        } else {
+            Ok(dir.join(name))
+        }
+    }
+
+    /// Applies the stored permissions and modification time to the restored file
+    pub fn apply(&self, file: &File) -> Result<(), Error> {
+        file.set_modified(self.modified())?;
+        file.set_permissions(self.permissions(file)?)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn permissions(&self, _: &File) -> Result<std::fs::Permissions, Error> {
+        use std::os::unix::fs::PermissionsExt;
+        // Never restore special bits such as setuid from an untrusted image
+        Ok(std::fs::Permissions::from_mode(self.mode & 0o777))
+    }
+
+    #[cfg(not(unix))]
+    fn permissions(&self, file: &File) -> Result<std::fs::Permissions, Error> {
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_readonly(self.mode & 0o200 == 0);
+        Ok(permissions)
+    }
+
+    fn modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.mtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metadata;
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+
+    fn metadata(name: &str) -> Metadata {
+        Metadata {
+            name: name.to_string(),
+            size: 3,
+            mode: 0o640,
+            mtime: 1584921600,
+        }
+    }
+
+    #[test]
+    fn test_new() {
+        let path = Path::new("./samples/secret.txt");
+        let metadata = Metadata::new(path, &File::open(path).unwrap()).unwrap();
+        assert_eq!(metadata.name, "secret.txt");
+        assert_eq!(metadata.size, 20);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut bytes = metadata("secret.txt").to_bytes();
+        bytes.extend_from_slice(&[1, 2, 3]);
+
+        let (result, secret) = Metadata::from_bytes(&bytes).unwrap();
+        assert_eq!(result, metadata("secret.txt"));
+        assert_eq!(secret, &[1, 2, 3]);
+
+        assert!(Metadata::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_path_in() {
+        let dir = Path::new("out");
+        let path = |name| metadata(name).path_in(dir);
+
+        assert_eq!(path("secret.txt").unwrap(), PathBuf::from("out/secret.txt"));
+        assert_eq!(
+            path("../../etc/passwd").unwrap(),
+            PathBuf::from("out/passwd")
+        );
+        assert_eq!(path("C:\\evil.exe").unwrap(), PathBuf::from("out/evil.exe"));
+        assert_eq!(path("/tmp/a\nb").unwrap(), PathBuf::from("out/ab"));
+        assert_eq!(path("C:evil.exe").unwrap(), PathBuf::from("out/Cevil.exe"));
+        assert!(path("").is_err());
+        assert!(path("..").is_err());
+        assert!(path("dir/").is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use ed25519_dalek::SigningKey;
+use image::ImageFormat;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::channels::{ChannelBits, Channels};
+use crate::pvd::Table;
+
+/// Settings shared by the encoder and the decoder
+#[derive(Default)]
+pub struct Options {
+    /// Password the secret is encrypted with
+    pub password: Option<String>,
+    /// Public keys the secret is encrypted to
+    pub recipients: Vec<PublicKey>,
+    /// Secret key opening a secret encrypted to its public key
+    pub identity: Option<StaticSecret>,
+    /// Key signing the payload
+    pub signing_key: Option<SigningKey>,
+    /// Whether to compress the secret before embedding it
+    pub compress: bool,
+    /// Passphrase scattering the payload over the image in a pseudo-random order
+    pub scatter: Option<String>,
+    /// Colour channels of the image holding the payload
+    pub channels: Channels,
+    /// Bits per sample of each channel, overriding the single number of bits for LSB embedding
+    pub channel_bits: Option<ChannelBits>,
+    /// Whether to fill the image bytes not holding the payload with random noise
+    pub noise: bool,
+    /// Whether to embed with LSB matching, moving bytes up or down instead of replacing their LSBs
+    pub matching: bool,
+    /// Whether to embed in the DCT coefficients of a JPEG image, which is saved as JPEG
+    pub dct: bool,
+    /// Whether to embed with Hamming codes, changing at most one sample LSB per block of samples
+    pub matrix: bool,
+    /// Whether to embed with syndrome-trellis codes, changing the samples in the most textured areas
+    pub adaptive: bool,
+    /// Range table to embed with pixel-value differencing, holding more bits in pairs of contrasting samples
+    pub pvd: Option<Table>,
+    /// Neighbourhood variance the image samples must exceed to hold the payload
+    pub texture: Option<u16>,
+    /// Reed-Solomon parity bytes per codeword of 255 bytes, correcting up to half as many corrupted bytes
+    pub ecc: Option<u8>,
+    /// Format the image is saved in, overriding the one implied by the output extension
+    pub format: Option<ImageFormat>,
+    /// Whether to save the image in a lossy format even though the payload is lost
+    pub allow_lossy: bool,
+    /// Whether to decode the saved image and check it holds the embedded payload
+    pub verify: bool,
+}
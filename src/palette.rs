@@ -0,0 +1,430 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::Path;
+
+use image::ImageFormat;
+
+use crate::errors::Error;
+
+/// Signatures of the image formats that can store a palette
+const PNG_SIGNATURE: &[u8] = b"\x89PNG";
+const GIF_SIGNATURE: &[u8] = b"GIF8";
+
+/// CIELAB coordinates of an sRGB colour under the D65 white point, where distances follow perceived differences
+fn lab(color: [u8; 3]) -> [f32; 3] {
+    let [r, g, b] = color.map(|c| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    let [x, y, z] = [
+        (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047,
+        0.2126 * r + 0.7152 * g + 0.0722 * b,
+        (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883,
+    ]
+    .map(|t| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    });
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+/// Colour table of an indexed image, sorted so that neighbouring indices have similar colours.
+/// Changing the low bits of an index then only moves a pixel to a close colour of the palette
+pub struct Palette {
+    width: u32,
+    height: u32,
+    colors: Vec<[u8; 3]>,
+    alpha: Vec<u8>,
+}
+
+impl Palette {
+    /// Opens an indexed PNG or a single frame GIF, returns `None` for any other image.
+    /// The palette is returned sorted, together with the pixel indices into it
+    pub fn open(path: &Path) -> Result<Option<(Self, Vec<u8>)>, Error> {
+        let mut signature = [0; 4];
+        File::open(path)?.read_exact(&mut signature)?;
+
+        let indexed = match &signature[..] {
+            PNG_SIGNATURE => Self::open_png(path)?,
+            GIF_SIGNATURE => Some(Self::open_gif(path)?),
+            _ => None,
+        };
+
+        // An empty palette leaves no index to move pixels to
+        if let Some((palette, _)) = &indexed {
+            if palette.colors.is_empty() {
+                return Err(Error::UnsupportedImage);
+            }
+        }
+        Ok(indexed.map(|(palette, indices)| palette.padded().sorted(indices)))
+    }
+
+    fn open_png(path: &Path) -> Result<Option<(Self, Vec<u8>)>, Error> {
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().map_err(|_| Error::ImageReadWrite)?;
+
+        if info.color_type != png::ColorType::Indexed {
+            return Ok(None);
+        }
+
+        let mut packed = vec![0; reader.output_buffer_size()];
+        reader
+            .next_frame(&mut packed)
+            .map_err(|_| Error::ImageReadWrite)?;
+
+        let depth = info.bit_depth as usize;
+        let indices = packed
+            .chunks_exact(info.line_size)
+            .flat_map(|line| {
+                (0..info.width as usize).map(move |x| {
+                    let shift = 8 - depth - (x * depth) % 8;
+                    (line[x * depth / 8] >> shift) & ((1 << depth) - 1) as u8
+                })
+            })
+            .collect();
+
+        let png = reader.info();
+        let colors = png
+            .palette
+            .as_deref()
+            .ok_or(Error::ImageReadWrite)?
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect::<Vec<_>>();
+
+        // Entries missing from the transparency chunk are opaque
+        let mut alpha = png.trns.clone().unwrap_or_default();
+        alpha.resize(colors.len(), u8::MAX);
+
+        let palette = Palette {
+            width: info.width,
+            height: info.height,
+            colors,
+            alpha,
+        };
+        Ok(Some((palette, indices)))
+    }
+
+    fn open_gif(path: &Path) -> Result<(Self, Vec<u8>), Error> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options
+            .read_info(BufReader::new(File::open(path)?))
+            .map_err(|_| Error::ImageReadWrite)?;
+
+        let global = decoder.global_palette().map(|palette| palette.to_vec());
+        let frame = decoder
+            .read_next_frame()
+            .map_err(|_| Error::ImageReadWrite)?
+            .ok_or(Error::ImageReadWrite)?
+            .clone();
+
+        // Only the first frame would be written back, animations would be lost
+        if decoder
+            .next_frame_info()
+            .map_err(|_| Error::ImageReadWrite)?
+            .is_some()
+        {
+            return Err(Error::UnsupportedImage);
+        }
+
+        let colors: Vec<[u8; 3]> = frame
+            .palette
+            .as_ref()
+            .or(global.as_ref())
+            .ok_or(Error::ImageReadWrite)?
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect();
+
+        let alpha = (0..colors.len())
+            .map(|i| match frame.transparent {
+                Some(transparent) if transparent as usize == i => 0,
+                _ => u8::MAX,
+            })
+            .collect();
+
+        let palette = Palette {
+            width: frame.width as u32,
+            height: frame.height as u32,
+            colors,
+            alpha,
+        };
+        Ok((palette, frame.buffer.into_owned()))
+    }
+
+    /// Pads the palette to the size of a GIF colour table, a power of two, with copies of its last opaque entry.
+    /// The GIF encoder would otherwise pad it with black entries, shifting the indices once sorted again.
+    /// The copies sort right next to their entry, so moving a pixel to one of them changes nothing visible
+    fn padded(mut self) -> Self {
+        let size = self.colors.len().next_power_of_two().max(2);
+        let entry = self
+            .alpha
+            .iter()
+            .rposition(|&a| a == u8::MAX)
+            .unwrap_or(self.colors.len().saturating_sub(1));
+
+        if let (Some(&color), Some(&alpha)) = (self.colors.get(entry), self.alpha.get(entry)) {
+            self.colors.resize(size, color);
+            self.alpha.resize(size, alpha);
+        }
+        self
+    }
+
+    /// Sorts the palette by transparency, then chains the entries of each transparency level from the darkest one,
+    /// always on to the nearest remaining colour in CIELAB. Neighbouring entries then have similar colours.
+    /// Ties go to the lowest index, so sorting an already sorted palette leaves it unchanged
+    fn sorted(self, indices: Vec<u8>) -> (Self, Vec<u8>) {
+        let lab: Vec<[f32; 3]> = self.colors.iter().map(|&color| lab(color)).collect();
+        let mut levels: Vec<usize> = (0..self.colors.len()).collect();
+        levels.sort_by_key(|&i| self.alpha[i]);
+
+        let mut order = Vec::with_capacity(levels.len());
+        for level in levels.chunk_by(|&a, &b| self.alpha[a] == self.alpha[b]) {
+            let mut remaining = level.to_vec();
+            remaining.sort_unstable();
+            let mut nearest = |from: Option<usize>| {
+                let distance = |i: usize| match from {
+                    Some(from) => (0..3).map(|c| (lab[i][c] - lab[from][c]).powi(2)).sum(),
+                    None => lab[i][0],
+                };
+                let position = (0..remaining.len())
+                    .reduce(|best, j| {
+                        if distance(remaining[j]) < distance(remaining[best]) {
+                            j
+                        } else {
+                            best
+                        }
+                    })
+                    .unwrap_or(0);
+                remaining.remove(position)
+            };
+
+            let mut last = nearest(None);
+            order.push(last);
+            for _ in 1..level.len() {
+                last = nearest(Some(last));
+                order.push(last);
+            }
+        }
+
+        let mut position = vec![0; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            position[old] = new as u8;
+        }
+
+        let palette = Palette {
+            colors: order.iter().map(|&i| self.colors[i]).collect(),
+            alpha: order.iter().map(|&i| self.alpha[i]).collect(),
+            ..self
+        };
+        let indices = indices
+            .into_iter()
+            .map(|i| position.get(i as usize).copied().unwrap_or(i))
+            .collect();
+        (palette, indices)
+    }
+
+    /// Whether every index sharing all but the given number of LSBs with this one has the same transparency.
+    /// Changing those LSBs then never turns a pixel transparent or opaque
+    pub fn same_alpha(&self, index: u16, bits: u8) -> bool {
+        let start = (index as usize >> bits) << bits;
+        match self.alpha.get(start..start + (1 << bits)) {
+            Some(block) => block.iter().all(|&a| a == block[0]),
+            None => false,
+        }
+    }
+
+    /// Number of colours in the palette
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    /// Width of the image in pixels
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Saves the indexed image as PNG or GIF, other formats can not hold the palette
+    pub fn save(&self, path: &Path, format: ImageFormat, indices: &[u8]) -> Result<(), Error> {
+        match format {
+            ImageFormat::Png => self.save_png(path, indices),
+            ImageFormat::Gif => self.save_gif(path, indices),
+            _ => Err(Error::UnsupportedImage),
+        }
+    }
+
+    fn save_png(&self, path: &Path, indices: &[u8]) -> Result<(), Error> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(self.colors.concat());
+
+        // The transparency chunk can leave out the opaque entries at the end of the palette
+        if let Some(last) = self.alpha.iter().rposition(|&a| a != u8::MAX) {
+            encoder.set_trns(self.alpha[..=last].to_vec());
+        }
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(indices))
+            .map_err(|_| Error::ImageReadWrite)
+    }
+
+    fn save_gif(&self, path: &Path, indices: &[u8]) -> Result<(), Error> {
+        // GIF dimensions are 16 bits wide
+        let (width, height) = match (u16::try_from(self.width), u16::try_from(self.height)) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => return Err(Error::UnsupportedImage),
+        };
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = gif::Encoder::new(file, width, height, &self.colors.concat())
+            .map_err(|_| Error::ImageReadWrite)?;
+
+        let frame = gif::Frame {
+            width,
+            height,
+            transparent: self.alpha.iter().position(|&a| a == 0).map(|i| i as u8),
+            buffer: Cow::Borrowed(indices),
+            ..gif::Frame::default()
+        };
+        encoder
+            .write_frame(&frame)
+            .map_err(|_| Error::ImageReadWrite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Palette;
+    use crate::errors::Error;
+    use crate::utils::Tmp;
+    use image::ImageFormat;
+    use std::path::Path;
+
+    fn palette() -> Palette {
+        Palette {
+            width: 2,
+            height: 2,
+            colors: vec![[255, 255, 255], [0, 0, 0], [255, 0, 0], [0, 0, 255]],
+            alpha: vec![255, 255, 255, 0],
+        }
+    }
+
+    #[test]
+    fn test_sorted() {
+        let (palette, indices) = palette().sorted(vec![0, 1, 2, 3]);
+        assert_eq!(
+            palette.colors,
+            vec![[0, 0, 255], [0, 0, 0], [255, 255, 255], [255, 0, 0]]
+        );
+        assert_eq!(palette.alpha, vec![0, 255, 255, 255]);
+        assert_eq!(indices, vec![2, 1, 3, 0]);
+
+        // Sorting again changes nothing
+        let (palette, indices) = palette.sorted(indices);
+        assert_eq!(palette.colors[0], [0, 0, 255]);
+        assert_eq!(indices, vec![2, 1, 3, 0]);
+
+        // Similar hues end up next to each other, whatever their luminance
+        let colors = vec![
+            [0, 0, 0],
+            [250, 0, 0],
+            [0, 0, 250],
+            [240, 10, 10],
+            [10, 10, 240],
+        ];
+        let (sorted, indices) = Palette {
+            alpha: vec![255; colors.len()],
+            colors,
+            ..palette
+        }
+        .sorted(vec![0, 1, 2, 3, 4]);
+        assert_eq!(sorted.colors[0], [0, 0, 0]);
+        assert_eq!(indices[1].abs_diff(indices[3]), 1);
+        assert_eq!(indices[2].abs_diff(indices[4]), 1);
+        assert_eq!(sorted.sorted(indices.clone()).1, indices);
+    }
+
+    #[test]
+    fn test_same_alpha() {
+        let (palette, _) = palette().sorted(vec![]);
+        assert!(!palette.same_alpha(0, 1));
+        assert!(!palette.same_alpha(1, 1));
+        assert!(palette.same_alpha(2, 1));
+        assert!(palette.same_alpha(3, 1));
+        assert!(!palette.same_alpha(3, 2));
+
+        // Blocks reaching past the palette can not be used
+        assert!(!palette.same_alpha(4, 1));
+    }
+
+    #[test]
+    fn test_padded() {
+        let padded = Palette {
+            colors: vec![
+                [10, 10, 10],
+                [200, 0, 0],
+                [0, 0, 255],
+                [255, 255, 255],
+                [0, 0, 0],
+            ],
+            alpha: vec![255, 255, 255, 255, 0],
+            ..palette()
+        }
+        .padded();
+        assert_eq!(padded.len(), 8);
+        assert!(padded.colors[5..].iter().all(|&c| c == [255, 255, 255]));
+        assert!(padded.alpha[5..].iter().all(|&a| a == 255));
+
+        // Palettes of a power of two size are left as they are
+        assert_eq!(padded.padded().len(), 8);
+        assert_eq!(palette().padded().colors, palette().colors);
+    }
+
+    #[test]
+    fn test_save_open() {
+        let mut tmp = Tmp::default();
+        for (extension, format) in &[("png", ImageFormat::Png), ("gif", ImageFormat::Gif)] {
+            let path = &tmp.path(extension);
+            palette().save(path, *format, &[0, 1, 2, 3]).unwrap();
+
+            // Opened sorted, with the same colours at each pixel
+            let (palette, indices) = Palette::open(path).unwrap().unwrap();
+            assert_eq!(palette.len(), 4);
+            assert_eq!(indices, vec![2, 1, 3, 0]);
+            assert_eq!(palette.alpha[0], 0);
+        }
+
+        // Too large for a GIF, nothing is written
+        let large = Palette {
+            width: 70_000,
+            height: 1,
+            ..palette()
+        };
+        let path = &tmp.path("gif");
+        assert!(matches!(
+            large.save(path, ImageFormat::Gif, &vec![0; 70_000]),
+            Err(Error::UnsupportedImage)
+        ));
+        assert!(!path.exists());
+
+        assert!(palette()
+            .save(&tmp.path("jpg"), ImageFormat::Jpeg, &[0, 1, 2, 3])
+            .is_err());
+        assert!(Palette::open(Path::new("./samples/the-matrix.jpg"))
+            .unwrap()
+            .is_none());
+    }
+}
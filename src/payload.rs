@@ -0,0 +1,258 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, VerifyingKey, SIGNATURE_LENGTH};
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+use flate2::Compression;
+
+use crate::crypto::{Encryption, TAG_SIZE};
+use crate::errors::Error;
+use crate::header::Header;
+use crate::metadata::Metadata;
+use crate::options::Options;
+
+/// Builds the payload to embed: the header followed by the secret and its metadata,
+/// and the signature of both when a signing key is given
+pub fn seal(secret_path: &Path, options: &Options) -> Result<Vec<u8>, Error> {
+    let mut file = File::open(secret_path)?;
+    let mut body = Metadata::new(secret_path, &file)?.to_bytes();
+    file.read_to_end(&mut body)?;
+
+    // Compression is skipped when it does not pay off, so the decoder relies on the header flag
+    let mut compressed = false;
+    if options.compress {
+        let deflated = deflate(&body)?;
+        if deflated.len() < body.len() {
+            body = deflated;
+            compressed = true;
+        }
+    }
+
+    let encryption = if let Some(password) = &options.password {
+        Some(Encryption::password(password)?)
+    } else if !options.recipients.is_empty() {
+        Some(Encryption::recipients(&options.recipients)?)
+    } else {
+        None
+    };
+
+    let mut header = Header::new(&body)?;
+    header.signed = options.signing_key.is_some();
+    header.compressed = compressed;
+
+    // The header is authenticated along with the ciphertext, so its flags and parameters can not be altered
+    if let Some((parameters, key)) = &encryption {
+        header.encryption = Some(parameters.clone());
+        header.length = (body.len() + TAG_SIZE)
+            .try_into()
+            .map_err(|_| Error::SecretTooLarge)?;
+        body = parameters.encrypt(key, &body, &header.associated_data())?;
+        header.checksum = crc32fast::hash(&body);
+    }
+
+    let mut payload = header.to_bytes();
+    payload.append(&mut body);
+
+    if let Some(signing_key) = &options.signing_key {
+        let signature = signing_key.sign(&payload);
+        payload.extend_from_slice(&signature.to_bytes());
+    }
+
+    Ok(payload)
+}
+
+/// Recovers the secret and its metadata from the payload bytes
+pub fn open<I>(mut bytes: I, options: &Options) -> Result<(Metadata, Vec<u8>), Error>
+where
+    I: Iterator<Item = u8>,
+{
+    let header = Header::read(&mut bytes)?;
+    let body: Vec<u8> = bytes.take(header.length as usize).collect();
+    header.verify(&body)?;
+
+    let aad = header.associated_data();
+    let body = match &header.encryption {
+        Some(encryption @ Encryption::Password { .. }) => {
+            let password = options.password.as_ref().ok_or(Error::WrongPassword)?;
+            encryption.decrypt(&encryption.password_key(password)?, &body, &aad)?
+        }
+        Some(encryption @ Encryption::Recipients { .. }) => {
+            let identity = options.identity.as_ref().ok_or(Error::WrongIdentity)?;
+            encryption.decrypt(&encryption.identity_key(identity)?, &body, &aad)?
+        }
+        None => body,
+    };
+
+    let body = if header.compressed {
+        inflate(&body)?
+    } else {
+        body
+    };
+
+    let (metadata, secret) = Metadata::from_bytes(&body)?;
+    Ok((metadata, secret.to_vec()))
+}
+
+/// Checks the payload signature against the verifying key, without opening the secret
+pub fn verify<I>(mut bytes: I, verifying_key: &VerifyingKey) -> Result<(), Error>
+where
+    I: Iterator<Item = u8>,
+{
+    let header = Header::read(&mut bytes)?;
+    let body: Vec<u8> = bytes.by_ref().take(header.length as usize).collect();
+    header.verify(&body)?;
+
+    if !header.signed {
+        return Err(Error::InvalidSignature);
+    }
+
+    let signature: Vec<u8> = bytes.take(SIGNATURE_LENGTH).collect();
+    let signature = Signature::from_slice(&signature).map_err(|_| Error::InvalidSignature)?;
+
+    let mut message = header.to_bytes();
+    message.extend_from_slice(&body);
+    verifying_key
+        .verify_strict(&message, &signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+fn deflate(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+fn inflate(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decoder = DeflateDecoder::new(Vec::new());
+    decoder
+        .write_all(bytes)
+        .and_then(|_| decoder.finish())
+        .map_err(|_| Error::CorruptedSecret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, seal, verify, Error, Header, Options};
+    use crate::utils::Tmp;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use std::path::Path;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    fn options(password: Option<&str>) -> Options {
+        Options {
+            password: password.map(String::from),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let payload = seal(Path::new("./samples/secret.txt"), &options(None)).unwrap();
+        let (metadata, secret) = open(payload.into_iter(), &options(None)).unwrap();
+        assert_eq!(metadata.name, "secret.txt");
+        assert_eq!(secret, b"The Matrix has you.\n");
+    }
+
+    #[test]
+    fn test_roundtrip_password() {
+        let payload = seal(
+            Path::new("./samples/secret.txt"),
+            &options(Some("red pill")),
+        )
+        .unwrap();
+        assert!(!payload.windows(10).any(|w| w == b"secret.txt"));
+
+        let (_, secret) = open(payload.iter().copied(), &options(Some("red pill"))).unwrap();
+        assert_eq!(secret, b"The Matrix has you.\n");
+
+        assert!(open(payload.iter().copied(), &options(Some("blue pill"))).is_err());
+        assert!(open(payload.iter().copied(), &options(None)).is_err());
+
+        // The header is authenticated with the secret, a flipped flag is caught despite a valid checksum
+        let mut tampered = payload.clone();
+        tampered[5] ^= 0b0000_1000;
+        assert!(matches!(
+            open(tampered.into_iter(), &options(Some("red pill"))),
+            Err(Error::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn test_roundtrip_recipients() {
+        let identity = StaticSecret::random_from_rng(OsRng);
+        let sealing = Options {
+            recipients: vec![PublicKey::from(&identity)],
+            ..Options::default()
+        };
+        let payload = seal(Path::new("./samples/secret.txt"), &sealing).unwrap();
+
+        let opening = Options {
+            identity: Some(identity),
+            ..Options::default()
+        };
+        let (_, secret) = open(payload.iter().copied(), &opening).unwrap();
+        assert_eq!(secret, b"The Matrix has you.\n");
+
+        let other = Options {
+            identity: Some(StaticSecret::random_from_rng(OsRng)),
+            ..Options::default()
+        };
+        assert!(open(payload.iter().copied(), &other).is_err());
+        assert!(open(payload.iter().copied(), &options(Some("red pill"))).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        let compress = Options {
+            compress: true,
+            ..Options::default()
+        };
+
+        // Highly compressible secret
+        let mut tmp = Tmp::default();
+        let path = &tmp.path("txt");
+        std::fs::write(path, vec![b'A'; 4096]).unwrap();
+        let payload = seal(path, &compress).unwrap();
+        assert!(payload.len() < 4096);
+        let header = Header::read(&mut payload.iter().copied()).unwrap();
+        assert!(header.compressed);
+        let (_, secret) = open(payload.into_iter(), &options(None)).unwrap();
+        assert_eq!(secret, vec![b'A'; 4096]);
+
+        // Too short to benefit from compression, so it is stored as is
+        let payload = seal(Path::new("./samples/secret.txt"), &compress).unwrap();
+        let header = Header::read(&mut payload.iter().copied()).unwrap();
+        assert!(!header.compressed);
+        let (_, secret) = open(payload.into_iter(), &options(None)).unwrap();
+        assert_eq!(secret, b"The Matrix has you.\n");
+    }
+
+    #[test]
+    fn test_signature() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let verifying_key = signing_key.verifying_key();
+        let sealing = Options {
+            signing_key: Some(signing_key),
+            ..Options::default()
+        };
+        let mut payload = seal(Path::new("./samples/secret.txt"), &sealing).unwrap();
+
+        assert!(verify(payload.iter().copied(), &verifying_key).is_ok());
+        let (_, secret) = open(payload.iter().copied(), &options(None)).unwrap();
+        assert_eq!(secret, b"The Matrix has you.\n");
+
+        let other = SigningKey::generate(&mut OsRng).verifying_key();
+        assert!(verify(payload.iter().copied(), &other).is_err());
+
+        // Tampering with the signature is detected, the secret itself is covered by the checksum
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert!(verify(payload.iter().copied(), &verifying_key).is_err());
+
+        let unsigned = seal(Path::new("./samples/secret.txt"), &options(None)).unwrap();
+        assert!(verify(unsigned.into_iter(), &verifying_key).is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use std::convert::TryInto;
+
+use crate::channels::ChannelBits;
+use crate::ecc;
+use crate::errors::Error;
+use crate::matrix;
+use crate::pvd::Table;
+use crate::utils::ByteMask;
+
+/// Number of image samples holding each bit of the preamble, read back by majority so a flipped LSB is outvoted
+const REPETITIONS: usize = 3;
+/// Number of image samples holding each byte of the preamble, one LSB each
+const SAMPLES_PER_BYTE: usize = 8 * REPETITIONS;
+/// Single LSB held by every preamble sample
+const BIT: ByteMask = ByteMask { bits: 1, mask: 1 };
+
+/// Mask of the first preamble byte holding the number of bits per sample, zero when given per channel
+const BITS_MASK: u8 = 0b0001_1111;
+/// Position of the embedding mode in the first preamble byte, above the number of bits
+const MODE_SHIFT: u8 = 5;
+/// Mask of the embedding mode, once shifted
+const MODE_MASK: u8 = 0b11;
+/// Flag of the first preamble byte set when a byte of further flags follows it
+const FLAG_EXTENDED: u8 = 0b1000_0000;
+/// Flag set when the payload samples are selected by their texture
+const FLAG_TEXTURE: u8 = 0b0000_0001;
+/// Flag set when the payload is protected by Reed-Solomon codes
+const FLAG_ECC: u8 = 0b0000_0010;
+
+/// Way the payload bits are spread over the selected image samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Every sample holds a chunk of payload bits in its LSBs
+    Lsb,
+    /// Every block of 2^k - 1 sample LSBs holds k payload bits in its Hamming syndrome
+    Matrix(u8),
+    /// Every block of that many sample LSBs holds a payload bit, through a syndrome-trellis code
+    Stc(u16),
+    /// Every pair of samples holds as many payload bits as the range table gives for their difference
+    Pvd(Table),
+}
+
+impl Mode {
+    /// Number of samples holding a chunk of payload bits
+    pub fn block_size(self) -> usize {
+        match self {
+            Mode::Lsb => 1,
+            Mode::Matrix(k) => matrix::block_size(k),
+            Mode::Stc(width) => width as usize,
+            Mode::Pvd(_) => 2,
+        }
+    }
+}
+
+/// Embedding parameters written to the first image samples, so the decoder needs no options to find the payload.
+/// The first byte holds the number of bits per sample and the mode, followed by the flags byte if any flag is set,
+/// the mode parameters if any, the bits of every channel if given per channel, the texture threshold
+/// and the number of parity bytes if set
+#[derive(Debug, PartialEq)]
+pub struct Preamble {
+    pub bits: u8,
+    pub mode: Mode,
+    /// Neighbourhood variance the samples holding the payload must exceed
+    pub texture: Option<u16>,
+    /// Bits per sample of each channel, `bits` being the most of them
+    pub channels: Option<ChannelBits>,
+    /// Reed-Solomon parity bytes per codeword of the payload
+    pub ecc: Option<u8>,
+}
+
+impl Preamble {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (mode, mut parameters) = match self.mode {
+            Mode::Lsb => (
+                0,
+                self.channels.map(ChannelBits::to_bytes).unwrap_or_default(),
+            ),
+            Mode::Matrix(k) => (1, vec![k]),
+            Mode::Stc(width) => (2, width.to_be_bytes().to_vec()),
+            Mode::Pvd(table) => (3, table.to_bytes()),
+        };
+        let bits = if self.channels.is_some() {
+            0
+        } else {
+            self.bits
+        };
+        let mut bytes = vec![bits | mode << MODE_SHIFT];
+
+        let mut flags = 0;
+        if self.texture.is_some() {
+            flags |= FLAG_TEXTURE;
+        }
+        if self.ecc.is_some() {
+            flags |= FLAG_ECC;
+        }
+        if flags != 0 {
+            bytes[0] |= FLAG_EXTENDED;
+            bytes.push(flags);
+        }
+
+        bytes.append(&mut parameters);
+        if let Some(texture) = self.texture {
+            bytes.extend_from_slice(&texture.to_be_bytes());
+        }
+        if let Some(parity) = self.ecc {
+            bytes.push(parity);
+        }
+        bytes
+    }
+
+    /// Parses the preamble from the given bytes, only the bytes belonging to it are consumed
+    pub fn read<I: Iterator<Item = u8>>(bytes: &mut I) -> Result<Self, Error> {
+        let first = bytes.next().ok_or(Error::InvalidHeader)?;
+        let mut bits = first & BITS_MASK;
+        let flags = match first & FLAG_EXTENDED {
+            0 => 0,
+            _ => match bytes.next() {
+                Some(flags) if flags & !(FLAG_TEXTURE | FLAG_ECC) == 0 => flags,
+                _ => return Err(Error::InvalidHeader),
+            },
+        };
+
+        let mode = match first >> MODE_SHIFT & MODE_MASK {
+            0 => Mode::Lsb,
+            1 => match bytes.next() {
+                Some(k) if bits == 1 && (1..=matrix::MAX_K).contains(&k) => Mode::Matrix(k),
+                _ => return Err(Error::InvalidHeader),
+            },
+            2 => match read_u16(bytes)? {
+                width if bits == 1 && width > 0 => Mode::Stc(width),
+                _ => return Err(Error::InvalidHeader),
+            },
+            3 if bits == 1 => Mode::Pvd(Table::read(bytes)?),
+            _ => return Err(Error::InvalidHeader),
+        };
+
+        // Only LSB embedding takes the bits per channel, the other modes use a single bit
+        let channels = if bits == 0 && mode == Mode::Lsb {
+            let channels = ChannelBits::read(bytes)?;
+            bits = channels.max();
+            Some(channels)
+        } else {
+            None
+        };
+
+        let texture = if flags & FLAG_TEXTURE != 0 {
+            Some(read_u16(bytes)?)
+        } else {
+            None
+        };
+
+        let ecc = if flags & FLAG_ECC != 0 {
+            match bytes.next() {
+                Some(parity) if (ecc::MIN_PARITY..=ecc::MAX_PARITY).contains(&parity) => {
+                    Some(parity)
+                }
+                _ => return Err(Error::InvalidHeader),
+            }
+        } else {
+            None
+        };
+
+        if (1..=16).contains(&bits) {
+            Ok(Preamble {
+                bits,
+                mode,
+                texture,
+                channels,
+                ecc,
+            })
+        } else {
+            Err(Error::InvalidHeader)
+        }
+    }
+
+    /// LSBs of the image samples holding the preamble, every bit repeated
+    pub fn to_bits(&self) -> impl Iterator<Item = u16> {
+        BIT.split(self.to_bytes())
+            .flat_map(|bit| std::iter::repeat_n(bit, REPETITIONS))
+    }
+
+    /// Parses the preamble from the LSBs of the given image samples, taking every bit by majority
+    pub fn read_bits<I: IntoIterator<Item = u16>>(samples: I) -> Result<Self, Error> {
+        let mut samples = samples.into_iter();
+        let bits = std::iter::from_fn(move || {
+            let votes: Vec<u16> = samples.by_ref().take(REPETITIONS).map(|s| s & 1).collect();
+            (votes.len() == REPETITIONS)
+                .then(|| (votes.iter().sum::<u16>() as usize * 2 > REPETITIONS) as u16)
+        });
+        Self::read(&mut BIT.join(bits))
+    }
+
+    /// Number of image samples holding the preamble
+    pub fn size(&self) -> usize {
+        self.to_bytes().len() * SAMPLES_PER_BYTE
+    }
+}
+
+/// Reads a big endian u16 from the next two bytes
+fn read_u16<I: Iterator<Item = u8>>(bytes: &mut I) -> Result<u16, Error> {
+    let value: Vec<u8> = bytes.take(2).collect();
+    value
+        .try_into()
+        .map(u16::from_be_bytes)
+        .map_err(|_| Error::InvalidHeader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mode, Preamble, Table, REPETITIONS, SAMPLES_PER_BYTE};
+
+    #[test]
+    fn test_roundtrip() {
+        for preamble in &[
+            Preamble {
+                bits: 2,
+                mode: Mode::Lsb,
+                texture: None,
+                channels: None,
+                ecc: None,
+            },
+            Preamble {
+                bits: 16,
+                mode: Mode::Lsb,
+                texture: Some(100),
+                channels: None,
+                ecc: None,
+            },
+            Preamble {
+                bits: 1,
+                mode: Mode::Matrix(7),
+                texture: None,
+                channels: None,
+                ecc: None,
+            },
+            Preamble {
+                bits: 1,
+                mode: Mode::Stc(745),
+                texture: Some(1000),
+                channels: None,
+                ecc: None,
+            },
+            Preamble {
+                bits: 1,
+                mode: Mode::Pvd(Table::default()),
+                texture: None,
+                channels: None,
+                ecc: None,
+            },
+            Preamble {
+                bits: 3,
+                mode: Mode::Lsb,
+                texture: Some(50),
+                channels: Some("r2,g1,b3".parse().unwrap()),
+                ecc: Some(32),
+            },
+            Preamble {
+                bits: 1,
+                mode: Mode::Matrix(3),
+                texture: None,
+                channels: None,
+                ecc: Some(2),
+            },
+        ] {
+            let mut bytes = preamble.to_bytes();
+            assert_eq!(bytes.len() * SAMPLES_PER_BYTE, preamble.size());
+
+            // Bytes after the preamble are left untouched
+            bytes.push(42);
+            let mut bytes = bytes.into_iter();
+            assert_eq!(Preamble::read(&mut bytes).unwrap(), *preamble);
+            assert_eq!(bytes.next(), Some(42));
+        }
+
+        // Images embedded before the modes existed only stored the number of bits
+        assert_eq!(
+            Preamble::read(&mut vec![2].into_iter()).unwrap().mode,
+            Mode::Lsb
+        );
+    }
+
+    #[test]
+    fn test_bits() {
+        let preamble = Preamble {
+            bits: 1,
+            mode: Mode::Stc(745),
+            texture: Some(1000),
+            channels: None,
+            ecc: Some(16),
+        };
+        let bits: Vec<u16> = preamble.to_bits().collect();
+        assert_eq!(bits.len(), preamble.size());
+
+        // A flipped copy of every bit is outvoted, the bits above the LSB are ignored
+        let flipped = bits.iter().enumerate().map(|(i, &bit)| {
+            (bit ^ (i % REPETITIONS == i / REPETITIONS % REPETITIONS) as u16) | 0b110
+        });
+        assert_eq!(Preamble::read_bits(flipped).unwrap(), preamble);
+
+        // Two flipped copies change the bit
+        let mut flipped = bits;
+        flipped[0] ^= 1;
+        flipped[1] ^= 1;
+        assert_ne!(Preamble::read_bits(flipped).ok(), Some(preamble));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let read = |bytes: &[u8]| Preamble::read(&mut bytes.iter().copied());
+        assert!(read(&[]).is_err());
+        assert!(read(&[0]).is_err());
+        assert!(read(&[17]).is_err());
+        assert!(read(&[0b0010_0001]).is_err());
+        assert!(read(&[0b0010_0001, 0]).is_err());
+        assert!(read(&[0b0010_0010, 3]).is_err());
+        assert!(read(&[0b0100_0001, 0, 0]).is_err());
+        assert!(read(&[0b0100_0001, 3]).is_err());
+        assert!(read(&[0b0110_0001, 1, 3]).is_err());
+        assert!(read(&[0b0110_0010, 1, 8]).is_err());
+        assert!(read(&[0b1000_0010, 1, 3]).is_err());
+        assert!(read(&[0b1000_0010, 4]).is_err());
+        assert!(read(&[0b1000_0010, 2, 1]).is_err());
+        assert!(read(&[0b1000_0010, 2]).is_err());
+        assert!(read(&[0, 0, 0, 0, 0]).is_err());
+        assert!(read(&[0, 2, 17, 3, 0]).is_err());
+        assert!(read(&[0b0010_0000, 2, 1, 3, 0]).is_err());
+    }
+}
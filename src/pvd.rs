@@ -0,0 +1,194 @@
+use std::str::FromStr;
+
+use crate::errors::Error;
+
+/// Largest number of ranges in a table
+const MAX_RANGES: usize = 16;
+
+/// Range table of pixel-value differencing. The differences between the two samples of a pair, 0 to 255,
+/// are split in consecutive ranges whose widths are powers of two. A pair holds as many bits as the
+/// logarithm of the width of the range its difference falls in, by moving the difference within the range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Table {
+    /// Bits held by the pairs of each range, the range width being 2^bits
+    bits: [u8; MAX_RANGES],
+    len: u8,
+}
+
+impl Table {
+    /// Creates the table from the bits of each range, `None` unless their widths add up to 256
+    pub fn new(bits: &[u8]) -> Option<Self> {
+        if bits.is_empty() || bits.len() > MAX_RANGES || bits.iter().any(|&b| b > 8) {
+            return None;
+        }
+        if bits.iter().map(|&b| 1u32 << b).sum::<u32>() != 256 {
+            return None;
+        }
+
+        let mut table = Table {
+            bits: [0; MAX_RANGES],
+            len: bits.len() as u8,
+        };
+        table.bits[..bits.len()].copy_from_slice(bits);
+        Some(table)
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![self.len];
+        bytes.extend_from_slice(&self.bits[..self.len as usize]);
+        bytes
+    }
+
+    /// Parses the table from the given bytes, only the bytes belonging to it are consumed
+    pub fn read<I: Iterator<Item = u8>>(bytes: &mut I) -> Result<Self, Error> {
+        let len = bytes.next().ok_or(Error::InvalidHeader)? as usize;
+        let bits: Vec<u8> = bytes.take(len).collect();
+        Table::new(&bits).ok_or(Error::InvalidHeader)
+    }
+
+    /// Lower bound and bits of the range the absolute difference falls in
+    fn range(&self, difference: i32) -> (i32, u8) {
+        let mut lower = 0;
+        for &bits in &self.bits[..self.len as usize] {
+            if difference < lower + (1 << bits) {
+                return (lower, bits);
+            }
+            lower += 1 << bits;
+        }
+        (lower, 0)
+    }
+
+    /// Number of bits the pair holds, `None` if moving its difference within its range could take a sample
+    /// out of 0 to `max`. Both signs are checked, so the pair is usable for the decoder too
+    pub fn capacity(&self, pair: (u16, u16), max: u16) -> Option<u8> {
+        let (average, difference) = split(pair);
+        let (lower, bits) = self.range(difference.abs());
+        let upper = lower + (1 << bits) - 1;
+
+        [lower, upper, -lower, -upper]
+            .iter()
+            .map(|&difference| join(average, difference))
+            .all(|(a, b)| (0..=max as i32).contains(&a) && (0..=max as i32).contains(&b))
+            .then_some(bits)
+    }
+
+    /// Returns the pair with the value held by the position of its difference within its range
+    pub fn embed(&self, pair: (u16, u16), value: u16) -> (u16, u16) {
+        let (average, difference) = split(pair);
+        let (lower, _) = self.range(difference.abs());
+        let embedded = lower + value as i32;
+
+        // The difference keeps its sign, a zero difference reads the same with either
+        let (a, b) = if difference < 0 {
+            join(average, -embedded)
+        } else {
+            join(average, embedded)
+        };
+        (a as u16, b as u16)
+    }
+
+    /// Recovers the value and number of bits held by the pair, `None` if the pair is not used
+    pub fn extract(&self, pair: (u16, u16), max: u16) -> Option<(u16, u8)> {
+        let bits = self.capacity(pair, max)?;
+        let (_, difference) = split(pair);
+        let (lower, _) = self.range(difference.abs());
+        Some(((difference.abs() - lower) as u16, bits))
+    }
+}
+
+/// Wu and Tsai's original table, with ranges 8, 8, 16, 32, 64 and 128 wide
+impl Default for Table {
+    fn default() -> Self {
+        Table::new(&[3, 3, 4, 5, 6, 7]).unwrap()
+    }
+}
+
+/// Parses the comma separated widths of the ranges, e.g. `8,8,16,32,64,128`
+impl FromStr for Table {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bits = s
+            .split(',')
+            .map(|width| match width.trim().parse::<u16>() {
+                Ok(width) if width.is_power_of_two() => Ok(width.trailing_zeros() as u8),
+                _ => Err(Error::InvalidTable),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Table::new(&bits).ok_or(Error::InvalidTable)
+    }
+}
+
+/// Average, rounded down, and difference of the samples of a pair
+fn split((a, b): (u16, u16)) -> (i32, i32) {
+    ((a as i32 + b as i32).div_euclid(2), b as i32 - a as i32)
+}
+
+/// Inverse of `split`, keeping the rounded down average of the pair
+fn join(average: i32, difference: i32) -> (i32, i32) {
+    let a = average - difference.div_euclid(2);
+    (a, a + difference)
+}
+
+/// Pairs the samples of each channel in the order they appear in the image, so most pairs are neighbours
+pub fn pairs(mut samples: Vec<usize>, stride: usize) -> Vec<[usize; 2]> {
+    samples.sort_by_key(|&i| (i % stride, i));
+    samples
+        .chunks_exact(2)
+        .map(|pair| [pair[0], pair[1]])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pairs, split, Table};
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            "8,8,16,32,64,128".parse::<Table>().unwrap(),
+            Table::default()
+        );
+        assert!("2,2,4,8,16,32,64,128".parse::<Table>().is_ok());
+        assert!("8,8,16,32,64".parse::<Table>().is_err());
+        assert!("8,8,16,32,64,100,28".parse::<Table>().is_err());
+        assert!("256".parse::<Table>().is_ok());
+        assert!("a,b".parse::<Table>().is_err());
+
+        let table = Table::default();
+        let bytes = table.to_bytes();
+        assert_eq!(bytes, vec![6, 3, 3, 4, 5, 6, 7]);
+        assert_eq!(Table::read(&mut bytes.into_iter()).unwrap(), table);
+        assert!(Table::read(&mut vec![2, 3, 3].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_embed_extract() {
+        let table = Table::default();
+
+        for &pair in &[(10, 3), (3, 10), (100, 100), (50, 90), (0, 7), (120, 160)] {
+            let bits = table.capacity(pair, 255).unwrap();
+            for value in 0..1 << bits {
+                let embedded = table.embed(pair, value);
+                assert_eq!(table.extract(embedded, 255), Some((value, bits)));
+
+                // The average of the pair is kept, so the change is split between both samples
+                assert_eq!(split(embedded).0, split(pair).0);
+            }
+        }
+
+        // Pairs whose samples could fall out of range are skipped
+        assert_eq!(table.capacity((0, 200), 255), None);
+        assert_eq!(table.capacity((255, 250), 255), None);
+        assert_eq!(table.extract((0, 200), 255), None);
+    }
+
+    #[test]
+    fn test_pairs() {
+        // Interleaved samples of two channels are paired within each channel
+        assert_eq!(
+            pairs(vec![0, 1, 2, 3, 4, 5, 6], 2),
+            vec![[0, 2], [4, 6], [1, 3]]
+        );
+    }
+}
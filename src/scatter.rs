@@ -0,0 +1,195 @@
+use std::borrow::Cow;
+
+use argon2::Argon2;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::channels::{Channel, ChannelBits};
+use crate::errors::Error;
+use crate::preamble::Mode;
+use crate::pvd;
+
+/// Fixed salt of the seed derivation, the order must only depend on the passphrase
+const SALT: &[u8] = b"piss/scatter";
+/// Salt of the noise seed derivation, so the noise is unrelated to the order
+const NOISE_SALT: &[u8] = b"piss/noise";
+/// Salt of the preamble order seed derivation, it is taken before the samples the payload is scattered over are known
+const PREAMBLE_SALT: &[u8] = b"piss/preamble";
+
+/// ChaCha20 generator seeded from the passphrase with Argon2id
+fn keyed_rng(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Rng, Error> {
+    let mut seed = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut seed)
+        .map_err(|_| Error::InvalidKey)?;
+    Ok(ChaCha20Rng::from_seed(seed))
+}
+
+/// Puts the items in the order they are used to embed the payload, in place.
+/// Left as they are unless a passphrase is given, in which case they follow a pseudo-random
+/// permutation generated by ChaCha20 seeded from the passphrase with Argon2id
+fn order<T>(items: &mut [T], passphrase: Option<&str>) -> Result<(), Error> {
+    if let Some(passphrase) = passphrase {
+        shuffle(items, keyed_rng(passphrase, SALT)?);
+    }
+    Ok(())
+}
+
+/// Fisher-Yates shuffle, implemented here so the order never depends on the rand version
+fn shuffle<T>(order: &mut [T], mut rng: ChaCha20Rng) {
+    for i in (1..order.len()).rev() {
+        let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+        order.swap(i, j);
+    }
+}
+
+/// Returns the samples in the order they hold the preamble, whose leading ones are taken for it.
+/// The first samples unless a passphrase is given, in which case they are shuffled with a generator
+/// of their own, so a scattered payload does not follow a preamble at a fixed position
+pub fn preamble<'a>(
+    samples: &'a [usize],
+    passphrase: Option<&str>,
+) -> Result<Cow<'a, [usize]>, Error> {
+    match passphrase {
+        Some(passphrase) => {
+            let mut order = samples.to_vec();
+            shuffle(&mut order, keyed_rng(passphrase, PREAMBLE_SALT)?);
+            Ok(Cow::Owned(order))
+        }
+        None => Ok(Cow::Borrowed(samples)),
+    }
+}
+
+/// Drops the samples taken by the preamble, the other ones keep their order
+pub fn skip(samples: &mut Vec<usize>, taken: &[usize]) {
+    let mut taken = taken.to_vec();
+    taken.sort_unstable();
+    samples.retain(|i| taken.binary_search(i).is_err());
+}
+
+/// Lays the payload out over the samples selected past the preamble, the same way when encoding and decoding.
+/// Returns the bits per sample of each channel of the image `layout` and the order of the samples holding them
+pub fn layout(
+    mut samples: Vec<usize>,
+    layout: &[Channel],
+    channel_bits: Option<ChannelBits>,
+    bits: u8,
+    mode: &Mode,
+    passphrase: Option<&str>,
+) -> Result<(Vec<u8>, Vec<usize>), Error> {
+    // Bits per sample of each channel, the other modes take chunks of a single width
+    let lanes: Vec<u8> = match channel_bits {
+        Some(channel_bits) => layout
+            .iter()
+            .map(|&channel| channel_bits.get(channel))
+            .collect(),
+        None => vec![bits],
+    };
+    // Channels given no bits hold nothing besides the preamble
+    samples.retain(|&i| lanes[i % lanes.len()] > 0);
+
+    // The selected samples are shuffled in place, kept as they are without a passphrase
+    match mode {
+        // Pairs of neighbouring samples are scattered as a whole
+        Mode::Pvd(_) => {
+            let mut pairs = pvd::pairs(samples, layout.len());
+            order(&mut pairs, passphrase)?;
+            samples = pairs.into_iter().flatten().collect();
+        }
+        _ => order(&mut samples, passphrase)?,
+    }
+
+    Ok((lanes, samples))
+}
+
+/// Generator of the noise filling the image bytes not holding the payload. Keyed by the passphrase if given,
+/// so only its holder can tell the noise apart from the payload, random otherwise
+pub fn noise(passphrase: Option<&str>) -> Result<ChaCha20Rng, Error> {
+    match passphrase {
+        Some(passphrase) => keyed_rng(passphrase, NOISE_SALT),
+        None => Ok(ChaCha20Rng::from_entropy()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{layout, noise, order, preamble, skip};
+    use crate::channels::Channel;
+    use crate::preamble::Mode;
+    use crate::pvd::Table;
+    use rand::RngCore;
+
+    fn ordered(len: usize, passphrase: Option<&str>) -> Vec<usize> {
+        let mut items: Vec<usize> = (0..len).collect();
+        order(&mut items, passphrase).unwrap();
+        items
+    }
+
+    #[test]
+    fn test_sequential() {
+        assert_eq!(ordered(5, None), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_permutation() {
+        let scattered = ordered(1000, Some("white rabbit"));
+        assert_ne!(scattered, ordered(1000, None));
+        assert_eq!(scattered, ordered(1000, Some("white rabbit")));
+        assert_ne!(scattered, ordered(1000, Some("black cat")));
+
+        let mut sorted = scattered;
+        sorted.sort_unstable();
+        assert_eq!(sorted, ordered(1000, None));
+    }
+
+    #[test]
+    fn test_preamble() {
+        let samples: Vec<usize> = (0..1000).map(|i| i * 3).collect();
+        assert_eq!(preamble(&samples, None).unwrap()[..], samples[..]);
+
+        // Scattered apart from the payload order, and the same again with the same passphrase
+        let scattered = preamble(&samples, Some("white rabbit")).unwrap();
+        assert_ne!(scattered[..24], samples[..24]);
+        assert_eq!(scattered, preamble(&samples, Some("white rabbit")).unwrap());
+        let mut payload = samples.clone();
+        order(&mut payload, Some("white rabbit")).unwrap();
+        assert_ne!(scattered[..24], payload[..24]);
+
+        // The remaining samples are left in order
+        let mut remaining = samples.clone();
+        skip(&mut remaining, &scattered[..24]);
+        assert_eq!(remaining.len(), samples.len() - 24);
+        assert!(remaining.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(remaining.iter().all(|i| !scattered[..24].contains(i)));
+    }
+
+    #[test]
+    fn test_layout() {
+        let rgb = [Channel::Red, Channel::Green, Channel::Blue];
+        let samples: Vec<usize> = (0..12).collect();
+
+        // Channels given no bits are left out
+        let channel_bits = Some("r2,b3".parse().unwrap());
+        let (lanes, order) =
+            layout(samples.clone(), &rgb, channel_bits, 3, &Mode::Lsb, None).unwrap();
+        assert_eq!(lanes, vec![2, 0, 3]);
+        assert_eq!(order, vec![0, 2, 3, 5, 6, 8, 9, 11]);
+
+        // PVD pairs of samples of the same channel stay together once scattered
+        let pvd = Mode::Pvd(Table::default());
+        let (lanes, order) = layout(samples, &rgb, None, 1, &pvd, Some("white rabbit")).unwrap();
+        assert_eq!(lanes, vec![1]);
+        assert!(order.chunks_exact(2).all(|pair| pair[0] % 3 == pair[1] % 3));
+    }
+
+    #[test]
+    fn test_noise() {
+        let keyed = |passphrase| noise(Some(passphrase)).unwrap().next_u64();
+        assert_eq!(keyed("white rabbit"), keyed("white rabbit"));
+        assert_ne!(keyed("white rabbit"), keyed("black cat"));
+        assert_ne!(
+            noise(None).unwrap().next_u64(),
+            noise(None).unwrap().next_u64()
+        );
+    }
+}
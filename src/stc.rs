@@ -0,0 +1,158 @@
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Constraint height of the code, the trellis has 2^HEIGHT states
+pub const HEIGHT: u32 = 7;
+
+/// Mask of the bits of a trellis state
+const STATES: u32 = (1 << HEIGHT) - 1;
+
+/// Columns of the submatrix of the parity check matrix, for blocks of `width` samples per payload bit.
+/// They are pseudo-random but only depend on the width, with their first and last rows set
+pub fn columns(width: u16) -> Vec<u32> {
+    let mut rng = ChaCha20Rng::seed_from_u64(width as u64);
+    (0..width)
+        .map(|_| rng.next_u32() & STATES | 1 | 1 << (HEIGHT - 1))
+        .collect()
+}
+
+/// Syndrome bit of the next block of samples, only their LSBs are taken into account.
+/// The state carries the contribution of the previous blocks to the following payload bits
+pub fn syndrome<I: IntoIterator<Item = u16>>(state: &mut u32, columns: &[u32], block: I) -> u16 {
+    for (column, sample) in columns.iter().zip(block) {
+        if sample & 1 == 1 {
+            *state ^= column;
+        }
+    }
+
+    let bit = (*state & 1) as u16;
+    *state >>= 1;
+    bit
+}
+
+/// Finds the sample LSBs holding the payload bits, one per block of samples, with the lowest total cost
+/// of the samples changed. Runs the Viterbi algorithm over the syndrome trellis of the code
+pub fn embed(lsbs: &[u16], costs: &[f32], bits: &[u16], columns: &[u32]) -> Vec<u16> {
+    let states = 1 << HEIGHT;
+    let mut weights = vec![f32::INFINITY; states];
+    let mut next = vec![0.0; states];
+    weights[0] = 0.0;
+
+    // Whether the cheapest path reaching each state sets the LSB of the sample, one bit per state
+    let mut paths: Vec<u128> = Vec::with_capacity(bits.len() * columns.len());
+
+    for &bit in bits {
+        for &column in columns {
+            let i = paths.len();
+            let (cost_0, cost_1) = if lsbs[i] & 1 == 1 {
+                (costs[i], 0.0)
+            } else {
+                (0.0, costs[i])
+            };
+
+            let mut path = 0;
+            for state in 0..states {
+                let weight_0 = weights[state] + cost_0;
+                let weight_1 = weights[state ^ column as usize] + cost_1;
+                if weight_1 < weight_0 {
+                    next[state] = weight_1;
+                    path |= 1 << state;
+                } else {
+                    next[state] = weight_0;
+                }
+            }
+            std::mem::swap(&mut weights, &mut next);
+            paths.push(path);
+        }
+
+        // Only the states holding the payload bit move on to the next block, shifting it out
+        for (state, weight) in next.iter_mut().enumerate() {
+            *weight = weights
+                .get(state << 1 | bit as usize)
+                .copied()
+                .unwrap_or(f32::INFINITY);
+        }
+        std::mem::swap(&mut weights, &mut next);
+    }
+
+    let mut state = (0..states)
+        .min_by(|&a, &b| weights[a].total_cmp(&weights[b]))
+        .unwrap_or(0);
+
+    // Backtrack the cheapest path, from the last sample to the first
+    let mut targets = vec![0; paths.len()];
+    for (block, &bit) in targets.chunks_mut(columns.len()).zip(bits).rev() {
+        state = state << 1 | bit as usize;
+        for (k, target) in block.iter_mut().enumerate().rev() {
+            if paths.pop().unwrap_or(0) >> state & 1 == 1 {
+                *target = 1;
+                state ^= columns[k] as usize;
+            }
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{columns, embed, syndrome, HEIGHT};
+    use rand::{RngCore, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    fn syndromes(lsbs: &[u16], columns: &[u32]) -> Vec<u16> {
+        let mut state = 0;
+        lsbs.chunks_exact(columns.len())
+            .map(|block| syndrome(&mut state, columns, block.iter().copied()))
+            .collect()
+    }
+
+    #[test]
+    fn test_columns() {
+        assert_eq!(columns(5), columns(5));
+        assert_ne!(columns(5)[..4], columns(4)[..]);
+        assert!(columns(9)
+            .iter()
+            .all(|column| column & 1 == 1 && column >> (HEIGHT - 1) == 1));
+    }
+
+    #[test]
+    fn test_embed() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        for &width in &[1, 2, 5, 16] {
+            let columns = columns(width);
+            let bits: Vec<u16> = (0..100).map(|_| rng.next_u32() as u16 & 1).collect();
+            let lsbs: Vec<u16> = (0..100 * width)
+                .map(|_| rng.next_u32() as u16 & 1)
+                .collect();
+            let costs = vec![1.0; lsbs.len()];
+
+            let targets = embed(&lsbs, &costs, &bits, &columns);
+            assert_eq!(syndromes(&targets, &columns), bits);
+
+            // Wider blocks need fewer changes per payload bit
+            let changes = lsbs.iter().zip(&targets).filter(|(a, b)| a != b).count();
+            if width >= 5 {
+                assert!(changes < bits.len() / 3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_costs() {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let columns = columns(8);
+        let bits: Vec<u16> = (0..64).map(|_| rng.next_u32() as u16 & 1).collect();
+        let lsbs = vec![0; 64 * 8];
+
+        // Changes avoid the expensive samples
+        let costs: Vec<f32> = (0..lsbs.len())
+            .map(|i| if i % 4 == 0 { 1.0 } else { 100.0 })
+            .collect();
+        let targets = embed(&lsbs, &costs, &bits, &columns);
+        assert_eq!(syndromes(&targets, &columns), bits);
+        assert!(targets
+            .iter()
+            .enumerate()
+            .all(|(i, &target)| target == 0 || i % 4 == 0));
+    }
+}
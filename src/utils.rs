@@ -1,88 +1,153 @@
 use crate::errors::Error;
 
-/// Represents a number of bits in a byte, its range is limited to [0, 8]
-/// Implements Iterator to iterate through non-overlapping masked bits of the byte
+/// Represents a number of low bits in an image sample, its range is limited to [1, 16].
+/// Splits a stream of bytes into chunks of that many bits, most significant first, and joins them back
 #[derive(Copy, Clone)]
 pub struct ByteMask {
     pub bits: u8,
-    pub mask: u8,
-    pub chunks: u8,
-    padded: bool,
-    byte: u8,
-    step: u8,
+    pub mask: u16,
 }
 
 impl ByteMask {
     pub fn new(bits: u8) -> Result<Self, Error> {
-        if (bits == 0) || (bits > 8) {
+        if (bits == 0) || (bits > 16) {
             Err(Error::InvalidNumberOfBits)
         } else {
-            let mask = (u16::pow(2, bits as u32) - 1) as u8;
-            let chunks = f32::ceil(8.0 / bits as f32) as u8;
-            let padded = 8 < (chunks * bits);
-
-            Ok(ByteMask {
-                bits,
-                mask,
-                chunks,
-                byte: 0,
-                step: 0,
-                padded,
-            })
+            let mask = (u32::pow(2, bits as u32) - 1) as u16;
+            Ok(ByteMask { bits, mask })
         }
     }
 
-    /// Sets the byte for which to iter over
-    pub fn set_byte(&mut self, byte: u8) -> Self {
-        self.byte = byte;
-        self.step = 0;
-        *self
+    /// Number of chunks needed to hold the given number of bytes, the last chunk is zero padded
+    pub fn chunks(self, bytes: usize) -> usize {
+        (bytes * 8).div_ceil(self.bits as usize)
     }
 
-    /// Inverse process, given a set of masked bytes, returns the original byte
-    pub fn join_chunks<'a, T>(self, chunks: &'a T) -> u8
+    /// Iterator over the chunks of bits of the given bytes
+    pub fn split<I>(self, bytes: I) -> impl Iterator<Item = u16>
     where
-        &'a T: IntoIterator<Item = &'a u8>,
+        I: IntoIterator<Item = u8>,
     {
-        let mut byte = 0;
-        let mut shift: u8 = 8;
-
-        for chunk in chunks {
-            shift = shift.saturating_sub(self.bits);
-            byte |= chunk << shift;
-        }
+        split_chunks(bytes, std::iter::repeat(self.bits))
+    }
 
-        byte
+    /// Inverse process, iterator over the bytes joined from the given chunks of bits.
+    /// The zero padding of the last chunk may yield a trailing zero byte, padding bits are dropped otherwise
+    pub fn join<I>(self, chunks: I) -> impl Iterator<Item = u8>
+    where
+        I: IntoIterator<Item = u16>,
+    {
+        join_chunks(chunks.into_iter().map(move |chunk| (chunk, self.bits)))
     }
 }
 
-impl Iterator for ByteMask {
-    type Item = u8;
+/// Iterator over the chunks of bits of the given bytes, each as wide as the next of the given widths.
+/// Ends with the bytes or the widths, the last chunk is zero padded
+pub fn split_chunks<I, W>(bytes: I, widths: W) -> impl Iterator<Item = u16>
+where
+    I: IntoIterator<Item = u8>,
+    W: IntoIterator<Item = u8>,
+{
+    let (mut bytes, mut widths) = (bytes.into_iter(), widths.into_iter());
+    let (mut buffer, mut buffered) = (0u32, 0u8);
+
+    std::iter::from_fn(move || {
+        let bits = widths.next()?;
+        while buffered < bits {
+            match bytes.next() {
+                Some(byte) => {
+                    buffer = (buffer << 8) | byte as u32;
+                    buffered += 8;
+                }
+                None if buffered == 0 => return None,
+                None => {
+                    buffer <<= bits - buffered;
+                    buffered = bits;
+                }
+            }
+        }
+
+        buffered -= bits;
+        Some(((buffer >> buffered) & ((1 << bits) - 1)) as u16)
+    })
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.step >= self.chunks {
-            return None;
+/// Iterator over the bytes joined from chunks of varying numbers of bits, given along with each chunk.
+/// Only the low bits of each chunk are taken, most significant first
+pub fn join_chunks<I>(chunks: I) -> impl Iterator<Item = u8>
+where
+    I: IntoIterator<Item = (u16, u8)>,
+{
+    let mut chunks = chunks.into_iter();
+    let (mut buffer, mut buffered) = (0u32, 0u8);
+
+    std::iter::from_fn(move || {
+        while buffered < 8 {
+            let (chunk, bits) = chunks.next()?;
+            let mask = (1u32 << bits) - 1;
+            buffer = (buffer << bits) | (chunk as u32 & mask);
+            buffered += bits;
         }
 
-        self.step += 1;
+        buffered -= 8;
+        Some((buffer >> buffered) as u8)
+    })
+}
 
-        if self.padded && (self.step == self.chunks) {
-            let shift = self.bits * self.step - 8;
-            Some(self.byte & (self.mask >> shift))
-        } else {
-            let shift = 8 - self.bits * self.step;
-            Some((self.byte >> shift) & self.mask)
+/// Files and directories written by a test at paths no other test uses,
+/// removed when dropped even if an assertion failed first
+#[cfg(test)]
+#[derive(Default)]
+pub struct Tmp(Vec<std::path::PathBuf>);
+
+#[cfg(test)]
+impl Tmp {
+    pub fn path(&mut self, extension: &str) -> std::path::PathBuf {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::path::PathBuf::from(format!(
+            "./samples/tmp-test-{}.{}",
+            COUNT.fetch_add(1, Ordering::Relaxed),
+            extension
+        ));
+        self.0.push(path.clone());
+        path
+    }
+
+    /// Removes the given path as well, for files written next to the others
+    pub fn add(&mut self, path: &std::path::Path) {
+        self.0.push(path.to_path_buf());
+    }
+
+    /// Creates an empty directory at a path of its own
+    pub fn dir(&mut self) -> std::path::PathBuf {
+        let path = self.path("d");
+        std::fs::create_dir(&path).unwrap();
+        path
+    }
+}
+
+#[cfg(test)]
+impl Drop for Tmp {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = if path.is_dir() {
+                std::fs::remove_dir_all(path)
+            } else {
+                std::fs::remove_file(path)
+            };
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ByteMask;
+    use super::{join_chunks, split_chunks, ByteMask};
 
     #[test]
     fn test_invalid_number() {
-        let result = ByteMask::new(9);
+        let result = ByteMask::new(17);
         assert!(result.is_err());
 
         let result = ByteMask::new(0);
@@ -94,157 +159,89 @@ mod tests {
         let mask = ByteMask::new(1).unwrap();
         assert_eq!(mask.bits, 1);
         assert_eq!(mask.mask, 0b00000001);
-        assert_eq!(mask.chunks, 8);
-        assert_eq!(mask.padded, false);
-        assert_eq!(mask.byte, 0);
-        assert_eq!(mask.step, 0);
-
-        let mask = ByteMask::new(2).unwrap();
-        assert_eq!(mask.bits, 2);
-        assert_eq!(mask.mask, 0b00000011);
-        assert_eq!(mask.chunks, 4);
-        assert_eq!(mask.padded, false);
-        assert_eq!(mask.byte, 0);
-        assert_eq!(mask.step, 0);
+        assert_eq!(mask.chunks(1), 8);
 
         let mask = ByteMask::new(3).unwrap();
         assert_eq!(mask.bits, 3);
         assert_eq!(mask.mask, 0b00000111);
-        assert_eq!(mask.chunks, 3);
-        assert_eq!(mask.padded, true);
-        assert_eq!(mask.byte, 0);
-        assert_eq!(mask.step, 0);
-
-        let mask = ByteMask::new(4).unwrap();
-        assert_eq!(mask.bits, 4);
-        assert_eq!(mask.mask, 0b00001111);
-        assert_eq!(mask.chunks, 2);
-        assert_eq!(mask.padded, false);
-        assert_eq!(mask.byte, 0);
-        assert_eq!(mask.step, 0);
-
-        let mask = ByteMask::new(5).unwrap();
-        assert_eq!(mask.bits, 5);
-        assert_eq!(mask.mask, 0b00011111);
-        assert_eq!(mask.chunks, 2);
-        assert_eq!(mask.padded, true);
-        assert_eq!(mask.byte, 0);
-        assert_eq!(mask.step, 0);
-
-        let mask = ByteMask::new(6).unwrap();
-        assert_eq!(mask.bits, 6);
-        assert_eq!(mask.mask, 0b00111111);
-        assert_eq!(mask.chunks, 2);
-        assert_eq!(mask.padded, true);
-        assert_eq!(mask.byte, 0);
-        assert_eq!(mask.step, 0);
-
-        let mask = ByteMask::new(7).unwrap();
-        assert_eq!(mask.bits, 7);
-        assert_eq!(mask.mask, 0b01111111);
-        assert_eq!(mask.chunks, 2);
-        assert_eq!(mask.padded, true);
-        assert_eq!(mask.byte, 0);
-        assert_eq!(mask.step, 0);
+        assert_eq!(mask.chunks(1), 3);
+        assert_eq!(mask.chunks(3), 8);
 
         let mask = ByteMask::new(8).unwrap();
         assert_eq!(mask.bits, 8);
         assert_eq!(mask.mask, 0b11111111);
-        assert_eq!(mask.chunks, 1);
-        assert_eq!(mask.padded, false);
-        assert_eq!(mask.byte, 0);
-        assert_eq!(mask.step, 0);
-    }
+        assert_eq!(mask.chunks(5), 5);
 
-    #[test]
-    fn test_set_byte() {
-        let mut mask = ByteMask::new(2).unwrap();
-        mask.step = 3;
-        mask.set_byte(5);
-        assert_eq!(mask.bits, 2);
-        assert_eq!(mask.chunks, 4);
-        assert_eq!(mask.padded, false);
-        assert_eq!(mask.byte, 5);
-        assert_eq!(mask.step, 0);
+        let mask = ByteMask::new(12).unwrap();
+        assert_eq!(mask.mask, 0b1111_1111_1111);
+        assert_eq!(mask.chunks(3), 2);
+        assert_eq!(mask.chunks(4), 3);
+
+        let mask = ByteMask::new(16).unwrap();
+        assert_eq!(mask.mask, u16::MAX);
+        assert_eq!(mask.chunks(3), 2);
     }
 
     #[test]
-    fn test_join_bits() {
-        let expected = 0b10010011;
+    fn test_split() {
+        let bytes = [0b10010011, 0b01011100];
 
         let mask = ByteMask::new(1).unwrap();
-        let bytes = mask.join_chunks(&vec![1, 0, 0, 1, 0, 0, 1, 1]);
-        assert_eq!(expected, bytes);
+        let chunks: Vec<u16> = mask.split(bytes[..1].iter().copied()).collect();
+        assert_eq!(chunks, vec![1, 0, 0, 1, 0, 0, 1, 1]);
 
         let mask = ByteMask::new(2).unwrap();
-        let bytes = mask.join_chunks(&vec![0b10, 0b01, 0b00, 0b11]);
-        assert_eq!(expected, bytes);
+        let chunks: Vec<u16> = mask.split(bytes[..1].iter().copied()).collect();
+        assert_eq!(chunks, vec![0b10, 0b01, 0b00, 0b11]);
 
         let mask = ByteMask::new(3).unwrap();
-        let bytes = mask.join_chunks(&vec![0b100, 0b100, 0b011]);
-        assert_eq!(expected, bytes);
+        let chunks: Vec<u16> = mask.split(bytes.iter().copied()).collect();
+        assert_eq!(chunks, vec![0b100, 0b100, 0b110, 0b101, 0b110, 0b000]);
 
-        let mask = ByteMask::new(4).unwrap();
-        let bytes = mask.join_chunks(&vec![0b1001, 0b0011]);
-        assert_eq!(expected, bytes);
+        let mask = ByteMask::new(12).unwrap();
+        let chunks: Vec<u16> = mask.split(bytes.iter().copied()).collect();
+        assert_eq!(chunks, vec![0b1001_0011_0101, 0b1100_0000_0000]);
 
-        let mask = ByteMask::new(5).unwrap();
-        let bytes = mask.join_chunks(&vec![0b10010, 0b00011]);
-        assert_eq!(expected, bytes);
+        let mask = ByteMask::new(16).unwrap();
+        let chunks: Vec<u16> = mask.split(bytes.iter().copied()).collect();
+        assert_eq!(chunks, vec![0b10010011_01011100]);
+    }
 
-        let mask = ByteMask::new(6).unwrap();
-        let bytes = mask.join_chunks(&vec![0b100100, 0b000011]);
-        assert_eq!(expected, bytes);
+    #[test]
+    fn test_join() {
+        let bytes: Vec<u8> = (0..=255).collect();
+
+        for bits in 1..=16 {
+            let mask = ByteMask::new(bits).unwrap();
+            let chunks: Vec<u16> = mask.split(bytes.iter().copied()).collect();
+            assert_eq!(chunks.len(), mask.chunks(bytes.len()));
+            assert!(chunks.iter().all(|&chunk| chunk <= mask.mask));
+
+            let joined: Vec<u8> = mask.join(chunks).collect();
+            assert_eq!(joined[..bytes.len()], bytes[..]);
+            assert!(joined[bytes.len()..].iter().all(|&b| b == 0));
+        }
+    }
 
-        let mask = ByteMask::new(7).unwrap();
-        let bytes = mask.join_chunks(&vec![0b1001001, 0b00000001]);
-        assert_eq!(expected, bytes);
+    #[test]
+    fn test_split_chunks() {
+        let bytes = [0b10010011, 0b01011100];
+        let chunks: Vec<u16> = split_chunks(bytes.iter().copied(), [3, 1, 0, 4, 16]).collect();
+        assert_eq!(chunks, vec![0b100, 0b1, 0, 0b0011, 0b0101_1100_0000_0000]);
 
-        let mask = ByteMask::new(8).unwrap();
-        let bytes = mask.join_chunks(&vec![0b10010011]);
-        assert_eq!(expected, bytes);
+        // Runs out of widths before the bytes
+        assert_eq!(split_chunks(bytes.iter().copied(), [2, 2]).count(), 2);
     }
 
     #[test]
-    fn test_iterator() {
-        let mut mask = ByteMask::new(1).unwrap();
-        let expected = vec![1, 0, 0, 1, 0, 0, 1, 1];
-        let result: Vec<u8> = mask.set_byte(0b10010011).collect();
-        assert_eq!(expected, result);
-
-        let mut mask = ByteMask::new(2).unwrap();
-        let expected = vec![0b10, 0b01, 0b00, 0b11];
-        let result: Vec<u8> = mask.set_byte(0b10010011).collect();
-        assert_eq!(expected, result);
-
-        let mut mask = ByteMask::new(3).unwrap();
-        let expected = vec![0b100, 0b100, 0b011];
-        let result: Vec<u8> = mask.set_byte(0b10010011).collect();
-        assert_eq!(expected, result);
-
-        let mut mask = ByteMask::new(4).unwrap();
-        let expected = vec![0b1001, 0b0011];
-        let result: Vec<u8> = mask.set_byte(0b10010011).collect();
-        assert_eq!(expected, result);
-
-        let mut mask = ByteMask::new(5).unwrap();
-        let expected = vec![0b10010, 0b00011];
-        let result: Vec<u8> = mask.set_byte(0b10010011).collect();
-        assert_eq!(expected, result);
-
-        let mut mask = ByteMask::new(6).unwrap();
-        let expected = vec![0b100100, 0b000011];
-        let result: Vec<u8> = mask.set_byte(0b10010011).collect();
-        assert_eq!(expected, result);
-
-        let mut mask = ByteMask::new(7).unwrap();
-        let expected = vec![0b1001001, 0b0000001];
-        let result: Vec<u8> = mask.set_byte(0b10010011).collect();
-        assert_eq!(expected, result);
-
-        let mut mask = ByteMask::new(8).unwrap();
-        let expected = vec![0b10010011];
-        let result: Vec<u8> = mask.set_byte(0b10010011).collect();
-        assert_eq!(expected, result);
+    fn test_join_chunks() {
+        // Chunks of zero bits hold nothing
+        let chunks = [(0b100, 3), (0b1, 1), (0, 0), (0b0011, 4), (0b0101_1100, 8)];
+        let joined: Vec<u8> = join_chunks(chunks.iter().copied()).collect();
+        assert_eq!(joined, vec![0b10010011, 0b01011100]);
+
+        // Bits above the chunk width are ignored
+        let joined: Vec<u8> = join_chunks(vec![(0xff0, 4), (0xf00, 4)]).collect();
+        assert_eq!(joined, vec![0]);
     }
 }